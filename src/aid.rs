@@ -0,0 +1,318 @@
+//! Feeds measurements from third party sensors that speak simple ASCII into the filter as
+//! external aiding.
+//!
+//! Lines are matched against a template like `$VEL,{speed}` where each `{name}` captures a
+//! number. Names pick the aiding command the value is sent with, `{_}` skips a value.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    time::Duration,
+};
+
+use clap::ArgMatches;
+use lordserial::parser::Lord;
+
+use crate::{
+    capture, device,
+    mip::{self, Command},
+    time, Error,
+};
+
+struct Aiding {
+    value: &'static str,
+    uncertainty: &'static str,
+    default_uncertainty: f32,
+    command: Command,
+}
+
+const AIDING: &[Aiding] = &[
+    // Odometer or other speed sensor, m/s
+    Aiding {
+        value: "speed",
+        uncertainty: "speed_uncertainty",
+        default_uncertainty: 0.1,
+        command: mip::SPEED_MEASUREMENT,
+    },
+    // True heading, degrees
+    Aiding {
+        value: "heading",
+        uncertainty: "heading_uncertainty",
+        default_uncertainty: 2.0,
+        command: mip::EXTERNAL_HEADING,
+    },
+    // Barometric or other altitude, meters above the ellipsoid
+    Aiding {
+        value: "altitude",
+        uncertainty: "altitude_uncertainty",
+        default_uncertainty: 1.0,
+        command: mip::HEIGHT_ABOVE_ELLIPSOID,
+    },
+];
+
+const SKIP: &str = "_";
+
+/// The aiding commands that can be sent, for help text.
+pub fn commands() -> Vec<Command> {
+    AIDING.iter().map(|a| a.command).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, Error> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+
+        while !rest.is_empty() {
+            match rest.find('{') {
+                Some(0) => {
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("Unclosed {{ in {}", template))?;
+                    let name = rest[1..end].trim();
+
+                    if let Some(Part::Field(_)) = parts.last() {
+                        return Err(format!(
+                            "Fields need a separator between them in {}",
+                            template
+                        )
+                        .into());
+                    }
+                    if name != SKIP && !is_input(name) {
+                        return Err(format!("Unknown aiding field {{{}}}", name).into());
+                    }
+
+                    parts.push(Part::Field(name.to_string()));
+                    rest = &rest[end + 1..];
+                }
+                Some(start) => {
+                    parts.push(Part::Literal(rest[..start].to_string()));
+                    rest = &rest[start..];
+                }
+                None => {
+                    parts.push(Part::Literal(rest.to_string()));
+                    rest = "";
+                }
+            }
+        }
+
+        if !parts
+            .iter()
+            .any(|p| matches!(p, Part::Field(name) if AIDING.iter().any(|a| a.value == name)))
+        {
+            return Err(format!("{} doesn't capture any aiding values", template).into());
+        }
+
+        Ok(Template { parts })
+    }
+
+    /// Values captured from `line`, or `None` if it doesn't match.
+    pub fn capture(&self, line: &str) -> Option<HashMap<String, f64>> {
+        let mut values = HashMap::new();
+        let mut rest = line;
+
+        for (i, part) in self.parts.iter().enumerate() {
+            match part {
+                Part::Literal(literal) => rest = rest.strip_prefix(literal.as_str())?,
+                Part::Field(name) => {
+                    // A field runs up to the next literal, or the end of the line
+                    let end = match self.parts.get(i + 1) {
+                        Some(Part::Literal(next)) => rest.find(next.as_str())?,
+                        _ => rest.len(),
+                    };
+
+                    if name != SKIP {
+                        values.insert(name.clone(), rest[..end].trim().parse().ok()?);
+                    }
+                    rest = &rest[end..];
+                }
+            }
+        }
+
+        Some(values)
+    }
+}
+
+fn is_input(name: &str) -> bool {
+    AIDING
+        .iter()
+        .any(|a| a.value == name || a.uncertainty == name)
+}
+
+fn encode(aiding: &Aiding, value: f32, uncertainty: f32, now: u64) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    match aiding.command {
+        mip::SPEED_MEASUREMENT => {
+            // Source, time of week, speed, uncertainty
+            data.push(1);
+            data.extend_from_slice(&(time::time_of_week(now) as f32).to_be_bytes());
+            data.extend_from_slice(&value.to_be_bytes());
+            data.extend_from_slice(&uncertainty.to_be_bytes());
+        }
+        mip::EXTERNAL_HEADING => {
+            // Heading, uncertainty, type (true heading)
+            data.extend_from_slice(&value.to_radians().to_be_bytes());
+            data.extend_from_slice(&uncertainty.to_radians().to_be_bytes());
+            data.push(1);
+        }
+        _ => {
+            // Timestamped on arrival, sensor frame 0, height, uncertainty, valid flags
+            data.extend_from_slice(&[3, 0]);
+            data.extend_from_slice(&0u64.to_be_bytes());
+            data.push(0);
+            data.extend_from_slice(&value.to_be_bytes());
+            data.extend_from_slice(&uncertainty.to_be_bytes());
+            data.extend_from_slice(&1u16.to_be_bytes());
+        }
+    }
+
+    data
+}
+
+pub fn run(lord: &mut Lord, matches: &ArgMatches) -> Result<(), Error> {
+    let matches = match matches.subcommand_matches("ascii") {
+        Some(matches) => matches,
+        None => return Err("Expected an aiding source, e.g. aid ascii".into()),
+    };
+
+    let template = Template::parse(matches.value_of("parser").unwrap())?;
+    let port_name = matches.value_of("port").unwrap();
+    let baud = matches.value_of("baud").unwrap().parse()?;
+    let mut sensor = serialport::new(port_name, baud)
+        .timeout(Duration::from_millis(100))
+        .open()?;
+
+    println!("Reading aiding measurements from {}", port_name);
+
+    let mut pending = Vec::new();
+    let mut buffer = [0u8; 256];
+    loop {
+        // Nothing to do with the IMU's own data here, just keep it from piling up
+        while lord.get_data().is_some() {}
+
+        match sensor.read(&mut buffer) {
+            Ok(count) => pending.extend_from_slice(&buffer[..count]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.into()),
+        }
+
+        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+
+            if let Some(values) = template.capture(line.trim_end()) {
+                send(lord, &values)?;
+            }
+        }
+    }
+}
+
+fn send(lord: &mut Lord, values: &HashMap<String, f64>) -> Result<(), Error> {
+    let now = capture::timestamp();
+
+    for aiding in AIDING {
+        let value = match values.get(aiding.value) {
+            Some(value) => *value as f32,
+            None => continue,
+        };
+        let uncertainty = values
+            .get(aiding.uncertainty)
+            .map(|u| *u as f32)
+            .unwrap_or(aiding.default_uncertainty);
+
+        match device::send(
+            lord,
+            aiding.command,
+            encode(aiding, value, uncertainty, now),
+        ) {
+            Ok(_) => println!("{}={} (±{})", aiding.value, value, uncertainty),
+            // A rejected measurement shouldn't stop the stream of them
+            Err(e) => eprintln!("Failed to send {}: {}", aiding.value, e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1000.5s into a GPS week
+    const NOW: u64 = 1_646_525_782_500_000_000;
+
+    fn frame(value: &str, measured: f32, uncertainty: f32) -> Vec<u8> {
+        let aiding = AIDING.iter().find(|a| a.value == value).unwrap();
+        mip::command(aiding.command, encode(aiding, measured, uncertainty, NOW))
+            .encode()
+            .unwrap()
+    }
+
+    #[test]
+    fn templates_capture_named_values() {
+        let template = Template::parse("$VEL,{speed},{_},{speed_uncertainty}*").unwrap();
+
+        let values = template.capture("$VEL,2.5, 7,0.2*").unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["speed"], 2.5);
+        assert_eq!(values["speed_uncertainty"], 0.2);
+
+        assert!(template.capture("$POS,2.5,7,0.2*").is_none());
+        assert!(template.capture("$VEL,fast,7,0.2*").is_none());
+        assert!(template.capture("$VEL,2.5").is_none());
+    }
+
+    #[test]
+    fn bad_templates_are_refused() {
+        let error = |template| Template::parse(template).unwrap_err().to_string();
+        assert!(error("{speed}{heading}").contains("need a separator"));
+        assert!(error("$VEL,{velocity}").contains("Unknown aiding field {velocity}"));
+        assert!(error("$VEL,{speed").contains("Unclosed"));
+        assert!(error("$VEL,{speed_uncertainty}").contains("doesn't capture any"));
+    }
+
+    #[test]
+    fn speed_frame() {
+        #[rustfmt::skip]
+        let expected = [
+            0x75, 0x65, 0x0D, 0x0F, 0x0F, 0x60,
+            0x01, 0x44, 0x7A, 0x20, 0x00, 0x40, 0x20, 0x00, 0x00, 0x3D, 0xCC, 0xCC, 0xCD,
+            0x46, 0x0F,
+        ];
+        assert_eq!(frame("speed", 2.5, 0.1), expected);
+    }
+
+    #[test]
+    fn heading_frame() {
+        #[rustfmt::skip]
+        let expected = [
+            0x75, 0x65, 0x0D, 0x0B, 0x0B, 0x17,
+            0x3F, 0xC9, 0x0F, 0xDB, 0x3D, 0x0E, 0xFA, 0x35, 0x01,
+            0x81, 0xB9,
+        ];
+        assert_eq!(frame("heading", 90.0, 2.0), expected);
+    }
+
+    #[test]
+    fn altitude_frame() {
+        #[rustfmt::skip]
+        let expected = [
+            0x75, 0x65, 0x13, 0x17, 0x17, 0x23,
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x44, 0x9A, 0x50, 0x00, 0x3F, 0x80, 0x00, 0x00, 0x00, 0x01,
+            0x2F, 0x7B,
+        ];
+        assert_eq!(frame("altitude", 1234.5, 1.0), expected);
+    }
+}
@@ -0,0 +1,305 @@
+//! Fixed size circular recording, the vehicle equivalent of a flight recorder.
+//!
+//! The ring file is a header followed by `capacity` bytes of capture records written round
+//! and round. The header tracks where the oldest record starts and how many bytes are in use,
+//! so the contents can be frozen out to a regular capture file, including after an unclean
+//! exit (anything written since the last sync is lost in that case). The header is synced
+//! before space it covers is reused, so it never points at a record that was overwritten.
+//! A clean stop freezes the ring and empties it, so only an unclean exit leaves data behind
+//! for the next run. The ring is written as `.partial` and only gets its real name once it's
+//! been emptied, so a ring with the real name never holds anything worth freezing.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    capture::{self, CaptureWriter, Kind},
+    partial::{self, PartialFile},
+    Error,
+};
+
+const MAGIC: &[u8; 8] = b"LORDRING";
+// Magic, capacity, start of the oldest record, bytes in use
+const HEADER_LEN: u64 = 8 * 4;
+const COPY_CHUNK: u64 = 1 << 20;
+// Once the ring is full records are dropped this much (or a sixteenth of the ring, if
+// that's less) ahead of what's needed, so the header is synced every so often rather than
+// on every write
+const EVICT_CHUNK: u64 = 64 * 1024;
+
+pub struct BlackBox {
+    file: File,
+    // Only while recording, a ring opened to be frozen is left where it is
+    partial: Option<PartialFile>,
+    path: PathBuf,
+    capacity: u64,
+    start: u64,
+    len: u64,
+    // Size of every record in the ring, oldest first, so we know how far to move the start
+    sizes: VecDeque<u32>,
+}
+
+impl BlackBox {
+    pub fn create(path: &Path, capacity: u64) -> Result<Self, Error> {
+        let partial = PartialFile::create(path)?;
+        let file = partial.try_clone()?;
+        file.set_len(HEADER_LEN + capacity)?;
+
+        let mut ring = BlackBox {
+            file,
+            partial: Some(partial),
+            path: path.to_path_buf(),
+            capacity,
+            start: 0,
+            len: 0,
+            sizes: VecDeque::new(),
+        };
+        ring.sync()?;
+        Ok(ring)
+    }
+
+    /// Open an existing ring, `.partial` or not, to freeze it. Only [`BlackBox::freeze`] is
+    /// meaningful afterwards.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(format!("{} is not a black box file", path.display()).into());
+        }
+
+        let word = |i: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&header[i * 8..(i + 1) * 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        Ok(BlackBox {
+            file,
+            partial: None,
+            path: partial::final_path(path).unwrap_or_else(|| path.to_path_buf()),
+            capacity: word(1),
+            start: word(2),
+            len: word(3),
+            sizes: VecDeque::new(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn write(&mut self, kind: Kind, time: u64, data: &[u8]) -> Result<(), Error> {
+        let record = capture::encode(kind, time, data);
+        let size = record.len() as u64;
+        if size > self.capacity {
+            return Err("Record is larger than the black box".into());
+        }
+
+        // Drop the oldest records until the new one fits with some room to spare
+        if self.len + size > self.capacity {
+            let free = (size + EVICT_CHUNK.min(self.capacity / 16)).min(self.capacity);
+            while self.capacity - self.len < free {
+                let oldest = match self.sizes.pop_front() {
+                    Some(oldest) => oldest as u64,
+                    None => break,
+                };
+                self.start = (self.start + oldest) % self.capacity;
+                self.len -= oldest;
+            }
+            self.sync()?;
+        }
+
+        let head = (self.start + self.len) % self.capacity;
+        self.write_at(head, &record)?;
+        self.len += size;
+        self.sizes.push_back(size as u32);
+
+        Ok(())
+    }
+
+    /// Persist the header so the ring can be frozen after a crash.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        // Records first, so the header never covers data that isn't on disk yet
+        self.file.sync_data()?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(MAGIC)?;
+        for word in &[self.capacity, self.start, self.len] {
+            self.file.write_all(&word.to_le_bytes())?;
+        }
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Copy everything currently in the ring, oldest first, to a new capture file.
+    pub fn freeze(&mut self) -> Result<PathBuf, Error> {
+        let out = self.frozen_path();
+        let mut writer = CaptureWriter::create(&out)?;
+
+        let mut copied = 0;
+        while copied < self.len {
+            let mut chunk = vec![0u8; COPY_CHUNK.min(self.len - copied) as usize];
+            self.read_at((self.start + copied) % self.capacity, &mut chunk)?;
+            writer.write_encoded(&chunk)?;
+            copied += chunk.len() as u64;
+        }
+
+        writer.finish()?;
+        Ok(out)
+    }
+
+    /// Empty the ring, once its contents are frozen or no longer wanted.
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.start = 0;
+        self.len = 0;
+        self.sizes.clear();
+        self.sync()
+    }
+
+    /// Empty the ring and give it its real name, once it's been frozen on a clean stop.
+    pub fn finish(mut self) -> Result<PathBuf, Error> {
+        self.clear()?;
+        match self.partial.take() {
+            Some(partial) => partial.commit(),
+            None => Ok(self.path),
+        }
+    }
+
+    fn frozen_path(&self) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "blackbox".to_string());
+        let secs = capture::timestamp() / 1_000_000_000;
+
+        self.path.with_file_name(format!("{}-{}.cap", stem, secs))
+    }
+
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Error> {
+        let first = ((self.capacity - offset) as usize).min(bytes.len());
+        self.file.seek(SeekFrom::Start(HEADER_LEN + offset))?;
+        self.file.write_all(&bytes[..first])?;
+
+        if first < bytes.len() {
+            self.file.seek(SeekFrom::Start(HEADER_LEN))?;
+            self.file.write_all(&bytes[first..])?;
+        }
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, bytes: &mut [u8]) -> Result<(), Error> {
+        let first = ((self.capacity - offset) as usize).min(bytes.len());
+        self.file.seek(SeekFrom::Start(HEADER_LEN + offset))?;
+        self.file.read_exact(&mut bytes[..first])?;
+
+        if first < bytes.len() {
+            self.file.seek(SeekFrom::Start(HEADER_LEN))?;
+            self.file.read_exact(&mut bytes[first..])?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse sizes like `512MB`, `2GiB` or a plain number of bytes.
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" | "k" => 1_000,
+        "mb" | "m" => 1_000_000,
+        "gb" | "g" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => return Err(format!("Unknown size unit in {}", size).into()),
+    };
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size {}", size))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size {} is too large", size).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::capture::CaptureReader;
+
+    fn ring_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lordcli-blackbox-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("ring.bin")
+    }
+
+    #[test]
+    fn freeze_after_crash_starts_on_a_record() {
+        let path = ring_path("crash");
+        let mut ring = BlackBox::create(&path, 10_000).unwrap();
+        for i in 0..300u64 {
+            ring.write(Kind::Packet, i, &[i as u8; 20]).unwrap();
+        }
+        ring.sync().unwrap();
+        // Round the ring a few more times and die without syncing
+        for i in 300..2000u64 {
+            ring.write(Kind::Packet, i, &[i as u8; 20]).unwrap();
+        }
+        drop(ring);
+
+        let frozen = BlackBox::open(&partial::partial_path(&path))
+            .unwrap()
+            .freeze()
+            .unwrap();
+        assert_eq!(frozen.parent(), path.parent());
+        assert!(frozen.to_string_lossy().contains("ring-"));
+        let mut reader = CaptureReader::open(&frozen).unwrap();
+        let mut times = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            assert_eq!(record.data, vec![record.time as u8; 20]);
+            times.push(record.time);
+        }
+        assert!(times.len() > 100);
+        assert!(times.windows(2).all(|w| w[1] == w[0] + 1));
+
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512MB").unwrap(), 512_000_000);
+        assert_eq!(parse_size("2 GiB").unwrap(), 2 << 30);
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert!(parse_size("20000000000GB").is_err());
+        assert!(parse_size("12 parsecs").is_err());
+    }
+
+    #[test]
+    fn finish_leaves_nothing_to_freeze() {
+        let path = ring_path("finish");
+        let partial = partial::partial_path(&path);
+        let mut ring = BlackBox::create(&path, 1000).unwrap();
+        ring.write(Kind::Event, 1, b"event").unwrap();
+        ring.sync().unwrap();
+        assert!(!BlackBox::open(&partial).unwrap().is_empty());
+
+        assert_eq!(ring.finish().unwrap(), path);
+        assert!(!partial.exists());
+        assert!(BlackBox::open(&path).unwrap().is_empty());
+
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+}
@@ -0,0 +1,186 @@
+//! Capture files hold raw MIP packets alongside out of band events, each stamped with the
+//! host time it was received.
+//!
+//! Layout is an 8 byte magic followed by records of
+//! `[kind: u8][unix time ns: u64 LE][length: u32 LE][data]`.
+//! Times are the host receive time in nanoseconds since the unix epoch.
+//!
+//! A finished file ends with a footer record (kind 0xFF) holding the length of all the
+//! records before it as a u64 LE, so a file cut short can be told apart from a complete one.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{partial::PartialFile, Error};
+
+pub const MAGIC: &[u8; 8] = b"LORDCAP1";
+const RECORD_HEADER_LEN: usize = 1 + 8 + 4;
+const FOOTER_KIND: u8 = 0xFF;
+// Far more than any packet or event, anything longer is a corrupt header
+const MAX_RECORD_LEN: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Packet,
+    Event,
+}
+
+impl Kind {
+    fn to_u8(self) -> u8 {
+        match self {
+            Kind::Packet => 0,
+            Kind::Event => 1,
+        }
+    }
+
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(Kind::Packet),
+            1 => Some(Kind::Event),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub kind: Kind,
+    pub time: u64,
+    pub data: Vec<u8>,
+}
+
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+pub fn encode(kind: Kind, time: u64, data: &[u8]) -> Vec<u8> {
+    encode_raw(kind.to_u8(), time, data)
+}
+
+fn encode_raw(kind: u8, time: u64, data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(RECORD_HEADER_LEN + data.len());
+    bytes.push(kind);
+    bytes.extend_from_slice(&time.to_le_bytes());
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+/// Writes to a `.partial` file that [`CaptureWriter::finish`] gives its real name.
+pub struct CaptureWriter {
+    out: BufWriter<PartialFile>,
+    // Bytes of records written so far, for the footer
+    written: u64,
+}
+
+impl CaptureWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut out = BufWriter::new(PartialFile::create(path)?);
+        out.write_all(MAGIC)?;
+        Ok(CaptureWriter { out, written: 0 })
+    }
+
+    pub fn write(&mut self, kind: Kind, time: u64, data: &[u8]) -> Result<(), Error> {
+        self.write_encoded(&encode(kind, time, data))
+    }
+
+    /// Append records that are already in capture format.
+    pub fn write_encoded(&mut self, records: &[u8]) -> Result<(), Error> {
+        self.out.write_all(records)?;
+        self.written += records.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.out.flush()?;
+        self.out.get_mut().sync()?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Error> {
+        let footer = encode_raw(FOOTER_KIND, timestamp(), &self.written.to_le_bytes());
+        self.out.write_all(&footer)?;
+
+        self.out.into_inner()?.commit()?;
+        Ok(())
+    }
+}
+
+pub struct CaptureReader {
+    input: BufReader<File>,
+    // Bytes of records read so far, to check the footer against
+    read: u64,
+    complete: bool,
+}
+
+impl CaptureReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut input = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(format!("{} is not a capture file", path.display()).into());
+        }
+
+        Ok(CaptureReader {
+            input,
+            read: 0,
+            complete: false,
+        })
+    }
+
+    /// Whether the footer has been reached, i.e. the file was finished properly.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// The next record, or `None` at the end of the file.
+    pub fn next_record(&mut self) -> Result<Option<Record>, Error> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        match self.input.read_exact(&mut header[..1]) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        self.input.read_exact(&mut header[1..])?;
+
+        let mut time = [0u8; 8];
+        time.copy_from_slice(&header[1..9]);
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[9..]);
+
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_RECORD_LEN {
+            return Err(format!("Corrupt capture record, {} bytes long", len).into());
+        }
+        let mut data = vec![0u8; len];
+        self.input.read_exact(&mut data)?;
+
+        if header[0] == FOOTER_KIND {
+            if data.len() != 8 || data[..] != self.read.to_le_bytes() {
+                return Err("Capture footer doesn't match the records before it".into());
+            }
+            self.complete = true;
+            return Ok(None);
+        }
+        self.read += (RECORD_HEADER_LEN + data.len()) as u64;
+
+        let kind = Kind::from_u8(header[0])
+            .ok_or_else(|| format!("Unknown capture record kind {}", header[0]))?;
+
+        Ok(Some(Record {
+            kind,
+            time: u64::from_le_bytes(time),
+            data,
+        }))
+    }
+}
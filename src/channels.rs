@@ -0,0 +1,417 @@
+//! Names for the values inside data packets, so they can be referenced as `filter.roll`
+//! or `gnss.alt` in expressions and exported as columns.
+
+use std::{collections::BTreeMap, fmt};
+
+use clap::ArgMatches;
+
+use crate::{
+    config::Config,
+    derived::Derived,
+    mip::{self, Frame},
+    Error,
+};
+
+use self::Type::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    U8,
+    U16,
+    F32,
+    F64,
+}
+
+impl Type {
+    pub fn size(self) -> usize {
+        match self {
+            Type::U8 => 1,
+            Type::U16 => 2,
+            Type::F32 => 4,
+            Type::F64 => 8,
+        }
+    }
+
+    fn read(self, data: &[u8]) -> f64 {
+        match self {
+            Type::U8 => data[0] as f64,
+            Type::U16 => u16::from_be_bytes([data[0], data[1]]) as f64,
+            Type::F32 => f32::from_be_bytes([data[0], data[1], data[2], data[3]]) as f64,
+            Type::F64 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&data[..8]);
+                f64::from_be_bytes(bytes)
+            }
+        }
+    }
+}
+
+/// Layout of a data field. Channels are packed back to back from the start of the field,
+/// anything after the last named channel (usually valid flags) is ignored.
+pub struct FieldSpec {
+    pub set: u8,
+    pub field: u8,
+    pub description: &'static str,
+    pub channels: &'static [(&'static str, Type)],
+}
+
+pub const FIELDS: &[FieldSpec] = &[
+    FieldSpec {
+        set: mip::IMU_DATA,
+        field: 0x04,
+        description: "Scaled accelerometer (g)",
+        channels: &[
+            ("imu.accel_x", F32),
+            ("imu.accel_y", F32),
+            ("imu.accel_z", F32),
+        ],
+    },
+    FieldSpec {
+        set: mip::IMU_DATA,
+        field: 0x05,
+        description: "Scaled gyro (rad/s)",
+        channels: &[
+            ("imu.gyro_x", F32),
+            ("imu.gyro_y", F32),
+            ("imu.gyro_z", F32),
+        ],
+    },
+    FieldSpec {
+        set: mip::IMU_DATA,
+        field: 0x06,
+        description: "Scaled magnetometer (gauss)",
+        channels: &[("imu.mag_x", F32), ("imu.mag_y", F32), ("imu.mag_z", F32)],
+    },
+    FieldSpec {
+        set: mip::IMU_DATA,
+        field: 0x07,
+        description: "Delta theta (rad)",
+        channels: &[
+            ("imu.dtheta_x", F32),
+            ("imu.dtheta_y", F32),
+            ("imu.dtheta_z", F32),
+        ],
+    },
+    FieldSpec {
+        set: mip::IMU_DATA,
+        field: 0x08,
+        description: "Delta velocity (g*s)",
+        channels: &[
+            ("imu.dvel_x", F32),
+            ("imu.dvel_y", F32),
+            ("imu.dvel_z", F32),
+        ],
+    },
+    FieldSpec {
+        set: mip::IMU_DATA,
+        field: 0x0A,
+        description: "Complementary filter quaternion",
+        channels: &[
+            ("imu.q0", F32),
+            ("imu.q1", F32),
+            ("imu.q2", F32),
+            ("imu.q3", F32),
+        ],
+    },
+    FieldSpec {
+        set: mip::IMU_DATA,
+        field: 0x0C,
+        description: "Complementary filter euler angles (rad)",
+        channels: &[("imu.roll", F32), ("imu.pitch", F32), ("imu.yaw", F32)],
+    },
+    FieldSpec {
+        set: mip::IMU_DATA,
+        field: 0x12,
+        description: "GPS timestamp",
+        channels: &[("imu.tow", F64), ("imu.week", U16)],
+    },
+    FieldSpec {
+        set: mip::IMU_DATA,
+        field: 0x17,
+        description: "Scaled ambient pressure (mbar)",
+        channels: &[("imu.pressure", F32)],
+    },
+    FieldSpec {
+        set: mip::GNSS_DATA,
+        field: 0x03,
+        description: "LLH position (deg, m)",
+        channels: &[
+            ("gnss.lat", F64),
+            ("gnss.lon", F64),
+            ("gnss.alt", F64),
+            ("gnss.msl_alt", F64),
+            ("gnss.h_acc", F32),
+            ("gnss.v_acc", F32),
+        ],
+    },
+    FieldSpec {
+        set: mip::GNSS_DATA,
+        field: 0x05,
+        description: "NED velocity (m/s, deg)",
+        channels: &[
+            ("gnss.vel_n", F32),
+            ("gnss.vel_e", F32),
+            ("gnss.vel_d", F32),
+            ("gnss.speed", F32),
+            ("gnss.ground_speed", F32),
+            ("gnss.heading", F32),
+            ("gnss.speed_acc", F32),
+            ("gnss.heading_acc", F32),
+        ],
+    },
+    FieldSpec {
+        set: mip::GNSS_DATA,
+        field: 0x09,
+        description: "GPS time",
+        channels: &[("gnss.tow", F64), ("gnss.week", U16)],
+    },
+    FieldSpec {
+        set: mip::GNSS_DATA,
+        field: 0x0B,
+        description: "Fix information",
+        channels: &[
+            ("gnss.fix_type", U8),
+            ("gnss.num_sv", U8),
+            ("gnss.fix_flags", U16),
+        ],
+    },
+    FieldSpec {
+        set: mip::FILTER_DATA,
+        field: 0x01,
+        description: "LLH position (deg, m)",
+        channels: &[
+            ("filter.lat", F64),
+            ("filter.lon", F64),
+            ("filter.alt", F64),
+        ],
+    },
+    FieldSpec {
+        set: mip::FILTER_DATA,
+        field: 0x02,
+        description: "NED velocity (m/s)",
+        channels: &[
+            ("filter.vel_n", F32),
+            ("filter.vel_e", F32),
+            ("filter.vel_d", F32),
+        ],
+    },
+    FieldSpec {
+        set: mip::FILTER_DATA,
+        field: 0x03,
+        description: "Attitude quaternion",
+        channels: &[
+            ("filter.q0", F32),
+            ("filter.q1", F32),
+            ("filter.q2", F32),
+            ("filter.q3", F32),
+        ],
+    },
+    FieldSpec {
+        set: mip::FILTER_DATA,
+        field: 0x05,
+        description: "Euler angles (rad)",
+        channels: &[
+            ("filter.roll", F32),
+            ("filter.pitch", F32),
+            ("filter.yaw", F32),
+        ],
+    },
+    FieldSpec {
+        set: mip::FILTER_DATA,
+        field: 0x10,
+        description: "Filter status",
+        channels: &[
+            ("filter.state", U16),
+            ("filter.dynamics_mode", U16),
+            ("filter.status_flags", U16),
+        ],
+    },
+    FieldSpec {
+        set: mip::FILTER_DATA,
+        field: 0x11,
+        description: "GPS timestamp",
+        channels: &[("filter.tow", F64), ("filter.week", U16)],
+    },
+];
+
+pub fn spec(set: u8, field: u8) -> Option<&'static FieldSpec> {
+    FIELDS.iter().find(|s| s.set == set && s.field == field)
+}
+
+pub fn is_known(name: &str) -> bool {
+    spec_of(name).is_some()
+}
+
+/// The field a channel comes from.
+pub fn spec_of(name: &str) -> Option<&'static FieldSpec> {
+    FIELDS
+        .iter()
+        .find(|s| s.channels.iter().any(|(channel, _)| *channel == name))
+}
+
+/// Every channel that can be pulled out of `frame`.
+pub fn decode(frame: &Frame) -> Vec<(String, f64)> {
+    let mut values = Vec::new();
+
+    for field in &frame.fields {
+        let spec = match spec(frame.descriptor, field.descriptor) {
+            Some(spec) => spec,
+            None => continue,
+        };
+
+        let mut offset = 0;
+        for (name, kind) in spec.channels {
+            if offset + kind.size() > field.data.len() {
+                break;
+            }
+
+            values.push((name.to_string(), kind.read(&field.data[offset..])));
+            offset += kind.size();
+        }
+    }
+
+    values
+}
+
+/// A field the decoder has no layout for, kept with its raw bytes so it can be reported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownField {
+    pub set: u8,
+    pub field: u8,
+    pub data: Vec<u8>,
+}
+
+impl UnknownField {
+    /// Name it goes by in outputs alongside the decoded channels, e.g. `unknown.0x80.0x99`.
+    pub fn channel(&self) -> String {
+        format!("unknown.0x{:02X}.0x{:02X}", self.set, self.field)
+    }
+
+    pub fn hex(&self) -> String {
+        self.data.iter().map(|b| format!("{:02X}", b)).collect()
+    }
+}
+
+impl fmt::Display for UnknownField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown-field set=0x{:02X} field=0x{:02X} len={} data={}",
+            self.set,
+            self.field,
+            self.data.len(),
+            self.hex()
+        )
+    }
+}
+
+pub struct Decoded {
+    pub values: Vec<(String, f64)>,
+    /// Every field in the frame that couldn't be decoded
+    pub unknown: Vec<UnknownField>,
+}
+
+/// [`decode`] plus the derived channels from the config file, that keeps track of the
+/// fields it couldn't decode, or refuses them with `--strict`.
+pub struct Decoder {
+    strict: bool,
+    derived: Derived,
+    unknown: BTreeMap<(u8, u8), u64>,
+}
+
+impl Decoder {
+    pub fn new(strict: bool, derived: Derived) -> Self {
+        Decoder {
+            strict,
+            derived,
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_matches(matches: &ArgMatches, config: &Config) -> Result<Self, Error> {
+        Ok(Decoder::new(
+            matches.is_present("strict"),
+            config.derived()?,
+        ))
+    }
+
+    /// Names of the derived channels from the config file.
+    pub fn derived_channels(&self) -> Vec<String> {
+        self.derived.names()
+    }
+
+    /// Whether `name` is a decoded or derived channel.
+    pub fn is_known(&self, name: &str) -> bool {
+        is_known(name) || self.derived.contains(name)
+    }
+
+    /// The decoded channels `name` is computed from, just `name` if it's decoded itself and
+    /// `None` if it's neither.
+    pub fn sources(&self, name: &str) -> Option<Vec<String>> {
+        Some(self.derived.sources(name)).filter(|_| self.is_known(name))
+    }
+
+    pub fn decode(&mut self, frame: &Frame) -> Result<Decoded, Error> {
+        let mut unknown = Vec::new();
+
+        for field in &frame.fields {
+            if spec(frame.descriptor, field.descriptor).is_some() {
+                continue;
+            }
+
+            let record = UnknownField {
+                set: frame.descriptor,
+                field: field.descriptor,
+                data: field.data.clone(),
+            };
+            if self.strict {
+                return Err(format!("Can't decode {}", record).into());
+            }
+
+            *self.unknown.entry((record.set, record.field)).or_insert(0) += 1;
+            unknown.push(record);
+        }
+
+        let mut values = decode(frame);
+        self.derived.extend(&mut values);
+
+        Ok(Decoded { values, unknown })
+    }
+
+    /// Whether `field` is the first of its kind seen, for reporting each one only once.
+    pub fn is_first(&self, field: &UnknownField) -> bool {
+        self.unknown.get(&(field.set, field.field)) == Some(&1)
+    }
+
+    /// How many times each `(set, field)` that couldn't be decoded was seen.
+    pub fn unknown_counts(&self) -> &BTreeMap<(u8, u8), u64> {
+        &self.unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn sources_of_decoded_derived_and_unknown_channels() {
+        let mut definitions = BTreeMap::new();
+        definitions.insert(
+            "tilt".to_string(),
+            "atan2(imu.accel_y, imu.accel_z)".to_string(),
+        );
+        let decoder = Decoder::new(false, Derived::new(&definitions).unwrap());
+
+        assert_eq!(
+            decoder.sources("imu.accel_x"),
+            Some(vec!["imu.accel_x".to_string()])
+        );
+        assert_eq!(
+            decoder.sources("tilt"),
+            Some(vec!["imu.accel_y".to_string(), "imu.accel_z".to_string()])
+        );
+        assert_eq!(decoder.sources("imu.nope"), None);
+    }
+}
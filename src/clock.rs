@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "virtual")]
+use std::sync::{Arc, Mutex};
+
+/// Source of time for anything that waits, times out or measures rates.
+pub trait Clock: Send + Sync {
+    /// Time elapsed since the clock was created.
+    fn now(&self) -> Duration;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Clock that only moves when told to. Sleeping advances it instantly.
+#[cfg(feature = "virtual")]
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Clone, Default)]
+pub struct VirtualClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+#[cfg(feature = "virtual")]
+#[cfg_attr(not(test), allow(dead_code))]
+impl VirtualClock {
+    pub fn new() -> Self {
+        VirtualClock::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(feature = "virtual")]
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -0,0 +1,195 @@
+//! Optional settings file given with `--config`, for things that depend on the setup rather
+//! than the run, e.g.
+//!
+//! ```toml
+//! [pacing]
+//! command_delay = "20ms"
+//! write_chunk = 32
+//! chunk_delay = "2ms"
+//!
+//! # Overrides for devices whose model name contains the key
+//! [pacing.model."GX3"]
+//! command_delay = "50ms"
+//! write_chunk = 16
+//!
+//! # Derived channels, see the derived module
+//! [channels]
+//! accel_mag = "sqrt(imu.accel_x^2 + imu.accel_y^2 + imu.accel_z^2)"
+//! ```
+
+use std::{collections::BTreeMap, fs, sync::Arc, time::Duration};
+
+use clap::ArgMatches;
+use serde::Deserialize;
+use serialport::SerialPort;
+
+use crate::{
+    clock::Clock,
+    derived::Derived,
+    device, time,
+    transport::{self, Pacing},
+    Error,
+};
+
+const DETECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pacing: PacingConfig,
+    #[serde(default)]
+    channels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PacingConfig {
+    command_delay: Option<String>,
+    write_chunk: Option<usize>,
+    chunk_delay: Option<String>,
+    #[serde(default)]
+    model: BTreeMap<String, PacingConfig>,
+}
+
+impl PacingConfig {
+    /// Apply whatever this sets on top of `pacing`.
+    fn apply(&self, pacing: &mut Pacing) -> Result<(), Error> {
+        if let Some(delay) = &self.command_delay {
+            pacing.command_delay = time::parse_duration(delay)?;
+        }
+        if let Some(chunk) = self.write_chunk {
+            pacing.write_chunk = chunk;
+        }
+        if let Some(delay) = &self.chunk_delay {
+            pacing.chunk_delay = time::parse_duration(delay)?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self, Error> {
+        let path = match matches.value_of("config") {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+
+        toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| format!("Failed to read {}: {}", path, e).into())
+    }
+
+    pub fn derived(&self) -> Result<Derived, Error> {
+        Derived::new(&self.channels).map_err(|e| format!("Invalid [channels]: {}", e).into())
+    }
+
+    /// Pacing from the file's defaults and the command line, for talking to a device
+    /// before its model is known.
+    pub fn base_pacing(&self, matches: &ArgMatches) -> Result<Pacing, Error> {
+        let mut pacing = Pacing::default();
+        self.pacing.apply(&mut pacing)?;
+        command_line(matches)?.apply(&mut pacing)?;
+        Ok(pacing)
+    }
+
+    /// Pacing for the device on `port`: the file's defaults, then any model specific
+    /// section, then the command line. The model is only asked for when it matters, after
+    /// throwing away anything stale on the port and as slowly as any model is paced.
+    pub fn pacing(
+        &self,
+        port: &dyn SerialPort,
+        clock: Arc<dyn Clock>,
+        matches: &ArgMatches,
+    ) -> Result<Pacing, Error> {
+        let mut pacing = Pacing::default();
+        self.pacing.apply(&mut pacing)?;
+
+        if !self.pacing.model.is_empty() {
+            // Asked over a handle of its own, the caller lines the port up again after
+            let slowest = self.slowest_pacing(self.base_pacing(matches)?)?;
+            let mut paced = transport::pace(port.try_clone()?, slowest, clock.clone());
+            // The reply is found among whatever follows, even if this can't line up
+            transport::resync(&mut *paced, &*clock).ok();
+            paced.set_timeout(Duration::from_millis(10))?;
+
+            match device::query_info(&mut *paced, &*clock, DETECT_TIMEOUT) {
+                Ok(info) => {
+                    for (model, overrides) in &self.pacing.model {
+                        if info.model_name.contains(model.as_str()) {
+                            overrides.apply(&mut pacing)?;
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Couldn't identify the device for model pacing: {}", e),
+            }
+        }
+
+        command_line(matches)?.apply(&mut pacing)?;
+        Ok(pacing)
+    }
+
+    /// The gentlest of `base` and `base` with each model's overrides.
+    fn slowest_pacing(&self, base: Pacing) -> Result<Pacing, Error> {
+        let mut slowest = base;
+        for overrides in self.pacing.model.values() {
+            let mut pacing = base;
+            overrides.apply(&mut pacing)?;
+
+            slowest.command_delay = slowest.command_delay.max(pacing.command_delay);
+            slowest.chunk_delay = slowest.chunk_delay.max(pacing.chunk_delay);
+            slowest.write_chunk = match (slowest.write_chunk, pacing.write_chunk) {
+                (0, chunk) | (chunk, 0) => chunk,
+                (a, b) => a.min(b),
+            };
+        }
+        Ok(slowest)
+    }
+}
+
+fn command_line(matches: &ArgMatches) -> Result<PacingConfig, Error> {
+    Ok(PacingConfig {
+        command_delay: matches.value_of("command-delay").map(String::from),
+        write_chunk: matches
+            .value_of("write-chunk")
+            .map(str::parse)
+            .transpose()?,
+        chunk_delay: matches.value_of("chunk-delay").map(String::from),
+        model: BTreeMap::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slowest_pacing_takes_the_gentlest_of_every_model() {
+        let config: Config = toml::from_str(
+            r#"
+[pacing]
+command_delay = "20ms"
+
+[pacing.model."GX3"]
+command_delay = "50ms"
+write_chunk = 32
+
+[pacing.model."GX4"]
+write_chunk = 16
+chunk_delay = "2ms"
+"#,
+        )
+        .unwrap();
+
+        let mut base = Pacing::default();
+        config.pacing.apply(&mut base).unwrap();
+        assert_eq!(
+            config.slowest_pacing(base).unwrap(),
+            Pacing {
+                command_delay: Duration::from_millis(50),
+                write_chunk: 16,
+                chunk_delay: Duration::from_millis(2),
+            }
+        );
+        assert_eq!(Config::default().slowest_pacing(base).unwrap(), base);
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use clap::ArgMatches;
+
+use crate::{
+    capture::{CaptureReader, Kind},
+    channels::{Decoded, Decoder},
+    config::Config,
+    feather::FeatherWriter,
+    mat::{self, MatWriter},
+    mip::Frame,
+    partial::PartialFile,
+    time::TimeFormatter,
+    Error,
+};
+
+pub const FORMATS: &[&str] = &["csv", "mat", "feather"];
+
+pub fn run(matches: &ArgMatches, config: &Config, times: &TimeFormatter) -> Result<(), Error> {
+    let input = matches.value_of("INPUT").unwrap();
+    let output = matches.value_of("OUTPUT").unwrap();
+    let format = match matches.value_of("to") {
+        Some(format) => format.to_string(),
+        None => format_of(output)
+            .ok_or_else(|| format!("Can't tell the format of {}, use --to", output))?
+            .to_string(),
+    };
+
+    let mut packets = Packets {
+        reader: CaptureReader::open(input)?,
+        decoder: Decoder::from_matches(matches, config)?,
+    };
+    let samples = match format.as_str() {
+        "csv" => to_csv(&mut packets, output, times)?,
+        "mat" => to_mat(&mut packets, output)?,
+        _ => to_feather(&mut packets, output)?,
+    };
+
+    println!("Converted {} samples from {} to {}", samples, input, output);
+    if !packets.reader.is_complete() {
+        eprintln!(
+            "{} has no footer and may be cut short, lordcli recover can finish it",
+            input
+        );
+    }
+    for ((set, field), count) in packets.decoder.unknown_counts() {
+        println!(
+            "Kept {} unknown fields 0x{:02X}/0x{:02X} as raw bytes",
+            count, set, field
+        );
+    }
+    Ok(())
+}
+
+/// Output format implied by a file's extension.
+pub fn format_of(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path)
+        .extension()?
+        .to_string_lossy()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "csv" => Some("csv"),
+        "mat" => Some("mat"),
+        "feather" | "arrow" => Some("feather"),
+        _ => None,
+    }
+}
+
+struct Packets {
+    reader: CaptureReader,
+    decoder: Decoder,
+}
+
+/// Call `f` with the time, data set and decoded contents of every data packet in the capture.
+fn for_each_packet<F>(packets: &mut Packets, mut f: F) -> Result<(), Error>
+where
+    F: FnMut(u64, u8, Decoded) -> Result<(), Error>,
+{
+    while let Some(record) = packets.reader.next_record()? {
+        if record.kind != Kind::Packet {
+            continue;
+        }
+
+        if let Some(frame) = Frame::parse(&record.data) {
+            let decoded = packets.decoder.decode(&frame)?;
+            for unknown in &decoded.unknown {
+                if packets.decoder.is_first(unknown) {
+                    eprintln!("{}", unknown);
+                }
+            }
+            f(record.time, frame.descriptor, decoded)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// One row per sample, `time,channel,value`, with times in the chosen --time-format.
+/// Fields that couldn't be decoded get a row like `unknown.0x80.0x99` with the bytes in hex.
+fn to_csv(packets: &mut Packets, output: &str, times: &TimeFormatter) -> Result<u64, Error> {
+    let mut out = BufWriter::new(PartialFile::create(output)?);
+    writeln!(out, "time,channel,value")?;
+
+    let mut samples = 0;
+    for_each_packet(packets, |time, _, decoded| {
+        let time = times.format(time);
+        for (name, value) in decoded.values {
+            // Display for f64 prints the shortest string that parses back to the same value
+            writeln!(out, "{},{},{}", time, name, value)?;
+            samples += 1;
+        }
+        for unknown in decoded.unknown {
+            writeln!(out, "{},{},{}", time, unknown.channel(), unknown.hex())?;
+        }
+        Ok(())
+    })?;
+
+    out.into_inner()?.commit()?;
+    Ok(samples)
+}
+
+/// One Nx2 `[time, value]` double matrix per channel, named like `filter_roll`. Times are
+/// seconds since the unix epoch. Fields that couldn't be decoded get a `[time, bytes...]`
+/// matrix named like `unknown_0x80_0x99`, padded with NaN where lengths differ.
+fn to_mat(packets: &mut Packets, output: &str) -> Result<u64, Error> {
+    let mut series: BTreeMap<String, (Vec<f64>, Vec<f64>)> = BTreeMap::new();
+    let mut unknown: BTreeMap<String, Vec<(f64, Vec<u8>)>> = BTreeMap::new();
+
+    for_each_packet(packets, |time, _, decoded| {
+        let time = time as f64 / 1e9;
+        for (name, value) in decoded.values {
+            let (times, values) = series.entry(name).or_default();
+            times.push(time);
+            values.push(value);
+        }
+        for field in decoded.unknown {
+            let channel = field.channel();
+            unknown.entry(channel).or_default().push((time, field.data));
+        }
+        Ok(())
+    })?;
+
+    let mut writer = MatWriter::create(output)?;
+    let mut samples = 0;
+    for (name, (times, values)) in series {
+        let rows = times.len();
+        let mut data = times;
+        data.extend(values);
+
+        writer.write_matrix(&mat::variable_name(&name), rows, 2, &data)?;
+        samples += rows as u64;
+    }
+    for (name, fields) in unknown {
+        let rows = fields.len();
+        let cols = 1 + fields.iter().map(|(_, d)| d.len()).max().unwrap_or(0);
+        // Column major, so the times first and then each byte position in turn
+        let mut data: Vec<f64> = fields.iter().map(|(time, _)| *time).collect();
+        for i in 0..cols - 1 {
+            data.extend(
+                fields
+                    .iter()
+                    .map(|(_, d)| d.get(i).map_or(f64::NAN, |b| *b as f64)),
+            );
+        }
+
+        writer.write_matrix(&mat::variable_name(&name), rows, cols, &data)?;
+    }
+    writer.finish()?;
+
+    Ok(samples)
+}
+
+/// One file per data set, see [`crate::feather`].
+fn to_feather(packets: &mut Packets, output: &str) -> Result<u64, Error> {
+    let mut writer = FeatherWriter::create(output, &packets.decoder.derived_channels())?;
+    let mut samples = 0;
+
+    for_each_packet(packets, |time, set, decoded| {
+        samples += decoded.values.len() as u64;
+        writer.write(time, set, &decoded.values)?;
+        for unknown in &decoded.unknown {
+            writer.write_unknown(time, unknown)?;
+        }
+        Ok(())
+    })?;
+
+    for path in writer.finish()? {
+        println!("Wrote {}", path.display());
+    }
+    Ok(samples)
+}
@@ -0,0 +1,204 @@
+//! Channels computed from other channels, defined in the `[channels]` section of the
+//! `--config` file and exported alongside the ones decoded from packets, e.g.
+//!
+//! ```toml
+//! [channels]
+//! accel_mag = "sqrt(imu.accel_x^2 + imu.accel_y^2 + imu.accel_z^2)"
+//! alt_agl = "gnss.alt - 112.3"
+//! ax = "imu.accel_x"
+//! ```
+//!
+//! A derived channel gets a value whenever one of its inputs does, using the latest value
+//! of the others, and can itself be used by other derived channels.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{channels, expr::Expr, Error};
+
+#[derive(Debug, Default)]
+pub struct Derived {
+    // In dependency order, so a channel is always evaluated after its inputs
+    channels: Vec<(String, Expr)>,
+    latest: HashMap<String, f64>,
+}
+
+impl Derived {
+    pub fn new(definitions: &BTreeMap<String, String>) -> Result<Self, Error> {
+        let mut pending = Vec::new();
+        for (name, source) in definitions {
+            // Anything that isn't a plain channel name couldn't be used in an expression
+            if Expr::parse(name).ok() != Some(Expr::Channel(name.clone())) {
+                return Err(format!("Invalid channel name {}", name).into());
+            }
+            if channels::is_known(name) {
+                return Err(format!("{} is already a decoded channel", name).into());
+            }
+
+            let expr = Expr::parse(source).map_err(|e| format!("Channel {}: {}", name, e))?;
+            for input in expr.channels() {
+                if !channels::is_known(input) && !definitions.contains_key(input) {
+                    return Err(format!("Channel {}: unknown channel {}", name, input).into());
+                }
+            }
+            pending.push((name.clone(), expr));
+        }
+
+        let mut ordered: Vec<(String, Expr)> = Vec::new();
+        while !pending.is_empty() {
+            let ready = pending.iter().position(|(_, expr)| {
+                expr.channels().iter().all(|input| {
+                    channels::is_known(input) || ordered.iter().any(|(name, _)| name == input)
+                })
+            });
+
+            match ready {
+                Some(i) => ordered.push(pending.remove(i)),
+                None => {
+                    let names: Vec<&str> = pending.iter().map(|(name, _)| name.as_str()).collect();
+                    return Err(
+                        format!("Channels depend on each other: {}", names.join(", ")).into(),
+                    );
+                }
+            }
+        }
+
+        Ok(Derived {
+            channels: ordered,
+            latest: HashMap::new(),
+        })
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.channels.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.channels.iter().any(|(channel, _)| channel == name)
+    }
+
+    /// The decoded channels `name` is computed from, through any other derived channels.
+    pub fn sources(&self, name: &str) -> Vec<String> {
+        let expr = match self.channels.iter().find(|(channel, _)| channel == name) {
+            Some((_, expr)) => expr,
+            None => return vec![name.to_string()],
+        };
+
+        let mut sources = Vec::new();
+        for input in expr.channels() {
+            for source in self.sources(input) {
+                if !sources.contains(&source) {
+                    sources.push(source);
+                }
+            }
+        }
+        sources
+    }
+
+    /// Add the derived channels that `values` updates to the end of it.
+    pub fn extend(&mut self, values: &mut Vec<(String, f64)>) {
+        if self.channels.is_empty() {
+            return;
+        }
+
+        self.latest.extend(values.iter().cloned());
+        for (name, expr) in &self.channels {
+            let inputs = expr.channels();
+            if !values
+                .iter()
+                .any(|(channel, _)| inputs.contains(&channel.as_str()))
+            {
+                continue;
+            }
+
+            let latest = &self.latest;
+            if let Some(value) = expr.eval(&|input| latest.get(input).copied()) {
+                self.latest.insert(name.clone(), value);
+                values.push((name.clone(), value));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn derived(definitions: &[(&str, &str)]) -> Result<Derived, Error> {
+        let definitions = definitions
+            .iter()
+            .map(|(name, source)| (name.to_string(), source.to_string()))
+            .collect();
+        Derived::new(&definitions)
+    }
+
+    #[test]
+    fn inputs_are_evaluated_first() {
+        // Alphabetically the other way round
+        let mut derived =
+            derived(&[("a_double", "b_half * 4"), ("b_half", "imu.accel_x / 2")]).unwrap();
+        assert_eq!(derived.names(), vec!["b_half", "a_double"]);
+
+        let mut values = vec![("imu.accel_x".to_string(), 3.0)];
+        derived.extend(&mut values);
+        assert_eq!(
+            values,
+            vec![
+                ("imu.accel_x".to_string(), 3.0),
+                ("b_half".to_string(), 1.5),
+                ("a_double".to_string(), 6.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_inputs_keep_their_latest_value() {
+        let mut derived = derived(&[("sum", "imu.accel_x + imu.accel_y")]).unwrap();
+
+        let mut values = vec![("imu.accel_x".to_string(), 1.0)];
+        derived.extend(&mut values);
+        assert_eq!(values.len(), 1);
+
+        let mut values = vec![("imu.accel_y".to_string(), 2.0)];
+        derived.extend(&mut values);
+        assert_eq!(values[1], ("sum".to_string(), 3.0));
+
+        // Nothing it depends on changed
+        let mut values = vec![("imu.gyro_x".to_string(), 5.0)];
+        derived.extend(&mut values);
+        assert_eq!(values.len(), 1);
+    }
+
+    #[test]
+    fn cycles_are_refused() {
+        let error = derived(&[("a", "b + 1"), ("b", "a + 1")]).unwrap_err();
+        assert_eq!(error.to_string(), "Channels depend on each other: a, b");
+
+        let error = derived(&[("a", "a + 1")]).unwrap_err();
+        assert!(error.to_string().contains("depend on each other: a"));
+    }
+
+    #[test]
+    fn unknown_inputs_and_bad_names_are_refused() {
+        let error = derived(&[("a", "imu.nope + 1")]).unwrap_err();
+        assert_eq!(error.to_string(), "Channel a: unknown channel imu.nope");
+
+        assert!(derived(&[("a b", "1")]).is_err());
+        assert!(derived(&[("imu.accel_x", "1")]).is_err());
+        assert!(derived(&[("a", "1 +")]).is_err());
+    }
+
+    #[test]
+    fn sources_go_through_other_derived_channels() {
+        let derived = derived(&[
+            ("mag", "hypot(imu.accel_x, imu.accel_y)"),
+            ("excess", "mag - imu.accel_x"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            derived.sources("excess"),
+            vec!["imu.accel_x", "imu.accel_y"]
+        );
+        assert_eq!(derived.sources("imu.gyro_x"), vec!["imu.gyro_x"]);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{io, time::Duration};
+
+use lordserial::parser::Lord;
+use serialport::SerialPort;
+
+use crate::{
+    clock::Clock,
+    mip::{self, Command, Frame, Scanner},
+    Error,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataSet {
+    pub name: &'static str,
+    pub descriptor: u8,
+    pub stream: u8,
+    pub format: Command,
+    pub base_rate: Command,
+    pub base_rate_field: u8,
+}
+
+pub const IMU: DataSet = DataSet {
+    name: "IMU",
+    descriptor: mip::IMU_DATA,
+    stream: 0x01,
+    format: mip::IMU_FORMAT,
+    base_rate: mip::IMU_BASE_RATE,
+    base_rate_field: 0x83,
+};
+
+pub const GNSS: DataSet = DataSet {
+    name: "GNSS",
+    descriptor: mip::GNSS_DATA,
+    stream: 0x02,
+    format: mip::GNSS_FORMAT,
+    base_rate: mip::GNSS_BASE_RATE,
+    base_rate_field: 0x84,
+};
+
+pub const FILTER: DataSet = DataSet {
+    name: "Filter",
+    descriptor: mip::FILTER_DATA,
+    stream: 0x03,
+    format: mip::FILTER_FORMAT,
+    base_rate: mip::FILTER_BASE_RATE,
+    base_rate_field: 0x8A,
+};
+
+pub const DATA_SETS: [DataSet; 3] = [IMU, GNSS, FILTER];
+
+// Reply field for reading the stream enable setting
+const STREAM_STATE_FIELD: u8 = 0x85;
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub firmware: u16,
+    pub model_name: String,
+    pub model_number: String,
+    pub serial_number: String,
+    pub options: String,
+}
+
+impl DeviceInfo {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        // Firmware version followed by five space padded 16 character strings
+        if data.len() < 2 + 5 * 16 {
+            return None;
+        }
+
+        let text = |i: usize| {
+            String::from_utf8_lossy(&data[2 + i * 16..2 + (i + 1) * 16])
+                .trim()
+                .to_string()
+        };
+
+        Some(DeviceInfo {
+            firmware: u16::from_be_bytes([data[0], data[1]]),
+            model_name: text(0),
+            model_number: text(1),
+            serial_number: text(2),
+            // Lot number sits between the serial and options
+            options: text(4),
+        })
+    }
+
+    pub fn from_reply(reply: &Frame) -> Result<Self, Error> {
+        reply
+            .field(0x81)
+            .and_then(|f| DeviceInfo::parse(&f.data))
+            .ok_or_else(|| "Malformed device info reply".into())
+    }
+
+    pub fn firmware_version(&self) -> String {
+        format!(
+            "{}.{}.{:02}",
+            self.firmware / 1000,
+            self.firmware / 100 % 10,
+            self.firmware % 100
+        )
+    }
+}
+
+/// Every command the data set helpers below can send, for help text.
+pub fn commands() -> Vec<Command> {
+    let mut commands = vec![mip::DEVICE_INFO];
+    for set in DATA_SETS.iter() {
+        commands.extend(&[set.base_rate, set.format]);
+    }
+    commands.push(mip::ENABLE_STREAM);
+    commands
+}
+
+/// Send a single command and return the reply, failing if the device doesn't ack it.
+pub fn send(lord: &mut Lord, command: Command, data: Vec<u8>) -> Result<Frame, Error> {
+    let reply = Frame::from_packet(&lord.send(mip::command(command, data).to_packet())?)?;
+    check_ack(reply, command)
+}
+
+/// Like [`send`] but straight over a port, for when there's no parser running on it yet.
+/// Anything else the device is streaming is skipped while waiting for the reply.
+pub fn send_raw(
+    port: &mut dyn SerialPort,
+    clock: &dyn Clock,
+    command: Command,
+    data: Vec<u8>,
+    timeout: Duration,
+) -> Result<Frame, Error> {
+    port.write_all(&mip::command(command, data).encode()?)?;
+
+    let mut scanner = Scanner::new();
+    let mut buffer = [0u8; 256];
+    let start = clock.now();
+
+    while clock.now() - start < timeout {
+        match port.read(&mut buffer) {
+            Ok(count) => scanner.push(&buffer[..count]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        while let Some((frame, _)) = scanner.next_frame() {
+            if frame.ack(command).is_some() {
+                return check_ack(frame, command);
+            }
+        }
+    }
+
+    Err(format!(
+        "No reply to command 0x{:02X}/0x{:02X}",
+        command.set, command.field
+    )
+    .into())
+}
+
+fn check_ack(reply: Frame, command: Command) -> Result<Frame, Error> {
+    match reply.ack(command) {
+        Some(0) => Ok(reply),
+        Some(code) => Err(format!(
+            "Command 0x{:02X}/0x{:02X} rejected with error 0x{:02X}",
+            command.set, command.field, code
+        )
+        .into()),
+        None => Err(format!(
+            "No ack for command 0x{:02X}/0x{:02X}",
+            command.set, command.field
+        )
+        .into()),
+    }
+}
+
+pub fn device_info(lord: &mut Lord) -> Result<DeviceInfo, Error> {
+    DeviceInfo::from_reply(&send(lord, mip::DEVICE_INFO, vec![])?)
+}
+
+/// [`device_info`] straight over a port.
+pub fn query_info(
+    port: &mut dyn SerialPort,
+    clock: &dyn Clock,
+    timeout: Duration,
+) -> Result<DeviceInfo, Error> {
+    DeviceInfo::from_reply(&send_raw(port, clock, mip::DEVICE_INFO, vec![], timeout)?)
+}
+
+pub fn base_rate(lord: &mut Lord, set: DataSet) -> Result<u16, Error> {
+    parse_base_rate(&send(lord, set.base_rate, vec![])?, set)
+}
+
+pub fn parse_base_rate(reply: &Frame, set: DataSet) -> Result<u16, Error> {
+    reply
+        .field(set.base_rate_field)
+        .filter(|f| f.data.len() >= 2)
+        .map(|f| u16::from_be_bytes([f.data[0], f.data[1]]))
+        .ok_or_else(|| format!("Malformed {} base rate reply", set.name).into())
+}
+
+pub fn set_format(lord: &mut Lord, set: DataSet, fields: &[(u8, u16)]) -> Result<(), Error> {
+    let mut data = vec![mip::APPLY, fields.len() as u8];
+    for (descriptor, decimation) in fields {
+        data.push(*descriptor);
+        data.extend_from_slice(&decimation.to_be_bytes());
+    }
+
+    send(lord, set.format, data)?;
+    Ok(())
+}
+
+pub fn enable_stream(lord: &mut Lord, set: DataSet, enable: bool) -> Result<(), Error> {
+    send(
+        lord,
+        mip::ENABLE_STREAM,
+        vec![mip::APPLY, set.stream, enable as u8],
+    )?;
+    Ok(())
+}
+
+/// Save the current message format and stream state as the startup settings.
+pub fn save(lord: &mut Lord, set: DataSet) -> Result<(), Error> {
+    send(lord, set.format, vec![mip::SAVE])?;
+    send(lord, mip::ENABLE_STREAM, vec![mip::SAVE, set.stream])?;
+    Ok(())
+}
+
+/// Replace the current message format and stream state with the saved startup settings.
+pub fn load_startup(lord: &mut Lord, set: DataSet) -> Result<(), Error> {
+    send(lord, set.format, vec![mip::LOAD])?;
+    send(lord, mip::ENABLE_STREAM, vec![mip::LOAD, set.stream])?;
+    Ok(())
+}
+
+/// The current message format as `(field descriptor, decimation)` pairs.
+pub fn read_format(lord: &mut Lord, set: DataSet) -> Result<Vec<(u8, u16)>, Error> {
+    let reply = send(lord, set.format, vec![mip::READ])?;
+    // The format comes back in a field with the same descriptor as the data set
+    let data = reply
+        .field(set.descriptor)
+        .map(|f| f.data.clone())
+        .filter(|d| !d.is_empty() && d.len() > d[0] as usize * 3)
+        .ok_or_else(|| format!("Malformed {} format reply", set.name))?;
+
+    Ok(data[1..]
+        .chunks_exact(3)
+        .take(data[0] as usize)
+        .map(|c| (c[0], u16::from_be_bytes([c[1], c[2]])))
+        .collect())
+}
+
+pub fn stream_enabled(lord: &mut Lord, set: DataSet) -> Result<bool, Error> {
+    send(lord, mip::ENABLE_STREAM, vec![mip::READ, set.stream])?
+        .field(STREAM_STATE_FIELD)
+        .filter(|f| f.data.len() >= 2)
+        .map(|f| f.data[1] != 0)
+        .ok_or_else(|| format!("Malformed {} stream state reply", set.name).into())
+}
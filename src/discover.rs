@@ -0,0 +1,168 @@
+use std::{sync::Arc, time::Duration};
+
+use clap::ArgMatches;
+use serialport::{ClearBuffer, SerialPort};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    device::{self, DeviceInfo},
+    lock,
+    mip::{self, Command},
+    transport::{self, Pacing},
+    Error,
+};
+
+// The same as discover's default --timeout
+const FIRST_TIMEOUT: Duration = Duration::from_millis(250);
+// Most likely first, so a hit usually comes on the first try
+const CANDIDATE_BAUDS: &[u32] = &[115200, 921600, 460800, 230400, 38400, 19200, 9600];
+
+/// What [`identify`] sends, in order.
+pub const COMMANDS: &[Command] = &[mip::PING, mip::DEVICE_INFO];
+
+pub fn run(matches: &ArgMatches, pacing: Pacing) -> Result<(), Error> {
+    let timeout = Duration::from_millis(matches.value_of("timeout").unwrap().parse()?);
+    let default_baud = [transport::BAUD_RATE];
+    let bauds: &[u32] = if matches.is_present("scan-bauds") {
+        CANDIDATE_BAUDS
+    } else {
+        &default_baud
+    };
+
+    let ports = serialport::available_ports()?;
+    println!("Scanning {} serial ports...", ports.len());
+
+    let mut found = 0;
+    for port in ports {
+        // Probing would steal bytes from whoever is reading it
+        if let Some(holder) = lock::holder(&port.port_name) {
+            eprintln!(
+                "{:<16} skipped: in use by lordcli pid {}",
+                port.port_name, holder.pid
+            );
+            continue;
+        }
+
+        for baud in bauds {
+            match probe(&port.port_name, *baud, pacing, timeout) {
+                Ok(Some(info)) => {
+                    println!(
+                        "{:<16} {:>7} baud  {} ({}) SN {} firmware {}",
+                        port.port_name,
+                        baud,
+                        info.model_name,
+                        info.model_number,
+                        info.serial_number,
+                        info.firmware_version()
+                    );
+                    found += 1;
+                    break;
+                }
+                Ok(None) => (),
+                // Busy or no permission, no point trying other bauds
+                Err(e) => {
+                    eprintln!("{:<16} skipped: {}", port.port_name, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    if found == 0 {
+        println!("No responsive devices found");
+    }
+
+    Ok(())
+}
+
+/// The first port with a device answering at the default baud rate, for commands that go
+/// looking when they aren't given a PORT.
+pub fn first(pacing: Pacing) -> Result<Option<String>, Error> {
+    for port in serialport::available_ports()? {
+        if lock::holder(&port.port_name).is_some() {
+            continue;
+        }
+
+        if let Ok(Some(info)) = probe(&port.port_name, transport::BAUD_RATE, pacing, FIRST_TIMEOUT)
+        {
+            println!("Found {} on {}", info.model_name, port.port_name);
+            return Ok(Some(port.port_name));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Ping `port` at `baud`, returning the device info if something answers.
+fn probe(
+    port: &str,
+    baud: u32,
+    pacing: Pacing,
+    timeout: Duration,
+) -> Result<Option<DeviceInfo>, Error> {
+    let port = serialport::new(port, baud)
+        .timeout(Duration::from_millis(10))
+        .open()?;
+    port.clear(ClearBuffer::All)?;
+
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock::new());
+    let mut port = transport::pace(port, pacing, clock.clone());
+    identify(&mut *port, &*clock, timeout)
+}
+
+/// Ping the device on `port` and ask what it is, `None` if nothing answers the ping.
+fn identify(
+    port: &mut dyn SerialPort,
+    clock: &dyn Clock,
+    timeout: Duration,
+) -> Result<Option<DeviceInfo>, Error> {
+    if device::send_raw(port, clock, mip::PING, vec![], timeout).is_err() {
+        return Ok(None);
+    }
+
+    Ok(Some(device::query_info(port, clock, timeout)?))
+}
+
+#[cfg(all(test, feature = "virtual"))]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::VirtualClock,
+        virtual_port::{Reply, ScriptedDevice, VirtualPort},
+    };
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn sent(port: &VirtualPort) -> Vec<Command> {
+        port.handle()
+            .received()
+            .iter()
+            .map(|f| Command {
+                set: f.descriptor,
+                field: f.fields[0].descriptor,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identify_sends_its_commands() {
+        let clock = VirtualClock::new();
+        let mut port = VirtualPort::scripted(ScriptedDevice::demo(), Arc::new(clock.clone()));
+        port.set_timeout(Duration::from_millis(10)).unwrap();
+
+        let info = identify(&mut port, &clock, TIMEOUT).unwrap().unwrap();
+        assert_eq!(info.model_name, "3DM-CV5-10");
+        assert_eq!(sent(&port), COMMANDS);
+    }
+
+    #[test]
+    fn nothing_answering_the_ping_is_no_device() {
+        let clock = VirtualClock::new();
+        let device = ScriptedDevice::demo().reply(mip::PING, Reply::Silent);
+        let mut port = VirtualPort::scripted(device, Arc::new(clock.clone()));
+        port.set_timeout(Duration::from_millis(10)).unwrap();
+
+        assert!(identify(&mut port, &clock, TIMEOUT).unwrap().is_none());
+        assert_eq!(sent(&port), &COMMANDS[..1]);
+    }
+}
@@ -0,0 +1,473 @@
+//! Small arithmetic expression language over channel values, e.g.
+//! `abs(filter.roll) > 30deg` or `sqrt(ax^2 + ay^2 + az^2)`.
+//!
+//! Everything evaluates to a number, comparisons and logic give 1 for true and 0 for false.
+//! Numbers can have an exponent like `2.5e-1`, and carry a `deg` suffix to be converted to
+//! radians.
+
+use std::{f64::consts::PI, fmt};
+
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    Neg,
+    Not,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Channel(String),
+    Unary(Op, Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+// Name and number of arguments of every function expressions can call
+const FUNCTIONS: &[(&str, usize)] = &[
+    ("abs", 1),
+    ("sqrt", 1),
+    ("sin", 1),
+    ("cos", 1),
+    ("tan", 1),
+    ("asin", 1),
+    ("acos", 1),
+    ("atan", 1),
+    ("atan2", 2),
+    ("deg", 1),
+    ("rad", 1),
+    ("min", 2),
+    ("max", 2),
+    ("hypot", 2),
+];
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("Unexpected {} in {}", token, source).into()),
+        }
+    }
+
+    /// Evaluate with channel values from `lookup`, `None` if any channel is missing.
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<f64>) -> Option<f64> {
+        let truth = |b: bool| if b { 1.0 } else { 0.0 };
+
+        Some(match self {
+            Expr::Number(n) => *n,
+            Expr::Channel(name) => lookup(name)?,
+            Expr::Unary(op, a) => {
+                let a = a.eval(lookup)?;
+                match op {
+                    Op::Neg => -a,
+                    _ => truth(a == 0.0),
+                }
+            }
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(lookup)?, b.eval(lookup)?);
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div => a / b,
+                    Op::Rem => a % b,
+                    Op::Pow => a.powf(b),
+                    Op::Lt => truth(a < b),
+                    Op::Le => truth(a <= b),
+                    Op::Gt => truth(a > b),
+                    Op::Ge => truth(a >= b),
+                    Op::Eq => truth(a == b),
+                    Op::Ne => truth(a != b),
+                    Op::And => truth(a != 0.0 && b != 0.0),
+                    Op::Or => truth(a != 0.0 || b != 0.0),
+                    Op::Neg | Op::Not => unreachable!(),
+                }
+            }
+            Expr::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| a.eval(lookup))
+                    .collect::<Option<Vec<f64>>>()?;
+                match name.as_str() {
+                    "abs" => args[0].abs(),
+                    "sqrt" => args[0].sqrt(),
+                    "sin" => args[0].sin(),
+                    "cos" => args[0].cos(),
+                    "tan" => args[0].tan(),
+                    "asin" => args[0].asin(),
+                    "acos" => args[0].acos(),
+                    "atan" => args[0].atan(),
+                    "atan2" => args[0].atan2(args[1]),
+                    "deg" => args[0].to_degrees(),
+                    "rad" => args[0].to_radians(),
+                    "min" => args[0].min(args[1]),
+                    "max" => args[0].max(args[1]),
+                    "hypot" => args[0].hypot(args[1]),
+                    _ => unreachable!(),
+                }
+            }
+        })
+    }
+
+    /// Names of every channel the expression reads.
+    pub fn channels(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.collect_channels(&mut names);
+        names
+    }
+
+    fn collect_channels<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => (),
+            Expr::Channel(name) => {
+                if !names.contains(&name.as_str()) {
+                    names.push(name)
+                }
+            }
+            Expr::Unary(_, a) => a.collect_channels(names),
+            Expr::Binary(_, a, b) => {
+                a.collect_channels(names);
+                b.collect_channels(names);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|a| a.collect_channels(names)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+        }
+    }
+}
+
+// Longest first so `<=` wins over `<`
+const SYMBOLS: &[&str] = &[
+    "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "^", "<", ">", "!", "(", ")", ",",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() || c == '.' {
+            let end = number_len(rest);
+            let mut number: f64 = rest[..end]
+                .parse()
+                .map_err(|_| format!("Invalid number {}", &rest[..end]))?;
+            rest = &rest[end..];
+
+            if rest.starts_with("deg") {
+                number *= PI / 180.0;
+                rest = &rest[3..];
+            } else if rest.starts_with("rad") {
+                rest = &rest[3..];
+            }
+
+            tokens.push(Token::Number(number));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(*s))
+                .ok_or_else(|| format!("Unexpected character '{}' in {}", c, source))?;
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// Length of the number at the start of `source`, including any exponent.
+fn number_len(source: &str) -> usize {
+    let mut end = source
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(source.len());
+
+    // Only an exponent if digits follow the `e`
+    if let Some(after) = source[end..].strip_prefix(['e', 'E']) {
+        let sign = usize::from(after.starts_with(['+', '-']));
+        let digits = after[sign..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(after.len() - sign);
+        if digits > 0 {
+            end += 1 + sign + digits;
+        }
+    }
+    end
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) if *s == symbol => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), Error> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(format!("Expected '{}'", symbol).into())
+        }
+    }
+
+    /// Parse a left associative chain of `ops` with `operand` between them.
+    fn chain(
+        &mut self,
+        ops: &[(&str, Op)],
+        operand: fn(&mut Parser) -> Result<Expr, Error>,
+    ) -> Result<Expr, Error> {
+        let mut expr = operand(self)?;
+
+        'outer: loop {
+            for (symbol, op) in ops {
+                if self.eat(symbol) {
+                    expr = Expr::Binary(*op, Box::new(expr), Box::new(operand(self)?));
+                    continue 'outer;
+                }
+            }
+            return Ok(expr);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, Error> {
+        self.chain(&[("||", Op::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, Error> {
+        self.chain(&[("&&", Op::And)], Parser::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, Error> {
+        self.chain(
+            &[
+                ("<=", Op::Le),
+                (">=", Op::Ge),
+                ("==", Op::Eq),
+                ("!=", Op::Ne),
+                ("<", Op::Lt),
+                (">", Op::Gt),
+            ],
+            Parser::sum,
+        )
+    }
+
+    fn sum(&mut self) -> Result<Expr, Error> {
+        self.chain(&[("+", Op::Add), ("-", Op::Sub)], Parser::product)
+    }
+
+    fn product(&mut self) -> Result<Expr, Error> {
+        self.chain(
+            &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)],
+            Parser::unary,
+        )
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        if self.eat("-") {
+            Ok(Expr::Unary(Op::Neg, Box::new(self.unary()?)))
+        } else if self.eat("!") {
+            Ok(Expr::Unary(Op::Not, Box::new(self.unary()?)))
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<Expr, Error> {
+        let base = self.atom()?;
+
+        // Right associative and binds tighter than a leading minus on its exponent
+        if self.eat("^") {
+            Ok(Expr::Binary(
+                Op::Pow,
+                Box::new(base),
+                Box::new(self.unary()?),
+            ))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, Error> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) if self.eat("(") => {
+                let mut args = Vec::new();
+                if !self.eat(")") {
+                    loop {
+                        args.push(self.or()?);
+                        if self.eat(")") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+
+                match FUNCTIONS.iter().find(|(f, _)| *f == name) {
+                    Some((_, arity)) if *arity == args.len() => Ok(Expr::Call(name, args)),
+                    Some((_, arity)) => {
+                        Err(
+                            format!("{} takes {} argument(s), got {}", name, arity, args.len())
+                                .into(),
+                        )
+                    }
+                    None => Err(format!("Unknown function {}", name).into()),
+                }
+            }
+            Some(Token::Ident(name)) => Ok(Expr::Channel(name)),
+            Some(Token::Symbol("(")) => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(token) => Err(format!("Unexpected {}", token).into()),
+            None => Err("Unexpected end of expression".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn eval(source: &str) -> Option<f64> {
+        let values: HashMap<&str, f64> = vec![("filter.roll", -0.75), ("a", 2.0), ("b", 3.0)]
+            .into_iter()
+            .collect();
+        Expr::parse(source)
+            .unwrap()
+            .eval(&|name| values.get(name).copied())
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(eval("1e3"), Some(1000.0));
+        assert_eq!(eval("2.5e-1"), Some(0.25));
+        assert_eq!(eval("1.5E+2"), Some(150.0));
+        assert_eq!(eval(".5"), Some(0.5));
+        assert_eq!(eval("180deg"), Some(PI));
+        assert_eq!(eval("2rad"), Some(2.0));
+        assert!(Expr::parse("1.2.3").is_err());
+        assert!(Expr::parse("1e").is_err());
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Some(7.0));
+        assert_eq!(eval("(1 + 2) * 3"), Some(9.0));
+        assert_eq!(eval("10 - 4 - 3"), Some(3.0));
+        assert_eq!(eval("7 % 4 * 2"), Some(6.0));
+        assert_eq!(eval("2 ^ 3 ^ 2"), Some(512.0));
+        assert_eq!(eval("a + b > 4 && b < 4"), Some(1.0));
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(eval("-2 ^ 2"), Some(-4.0));
+        assert_eq!(eval("2 ^ -1"), Some(0.5));
+        assert_eq!(eval("--a"), Some(2.0));
+        assert_eq!(eval("3 - -a"), Some(5.0));
+    }
+
+    #[test]
+    fn comparison_and_logic() {
+        assert_eq!(eval("a < b"), Some(1.0));
+        assert_eq!(eval("a >= b"), Some(0.0));
+        assert_eq!(eval("a <= 2 && a == 2"), Some(1.0));
+        assert_eq!(eval("a != 2 || b > 3"), Some(0.0));
+        assert_eq!(eval("!(a > b)"), Some(1.0));
+        assert_eq!(eval("!0 || 0"), Some(1.0));
+        assert_eq!(eval("abs(filter.roll) > 30deg"), Some(1.0));
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval("hypot(b, 4)"), Some(5.0));
+        assert_eq!(eval("max(a, b) - min(a, b)"), Some(1.0));
+        assert!(Expr::parse("sqrt(1, 2)").is_err());
+        assert!(Expr::parse("nope(1)").is_err());
+    }
+
+    #[test]
+    fn unknown_channels_have_no_value() {
+        assert_eq!(eval("missing > 1"), None);
+        assert_eq!(eval("a > 1 || missing > 1"), None);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(Expr::parse("").is_err());
+        assert!(Expr::parse("(a + b").is_err());
+        assert!(Expr::parse("a b").is_err());
+        assert!(Expr::parse("a $ b").is_err());
+    }
+
+    #[test]
+    fn channels_are_listed_once_in_order() {
+        let expr = Expr::parse("atan2(gnss.vel_e, gnss.vel_n) > 1e-2 && gnss.vel_e != 0").unwrap();
+        assert_eq!(expr.channels(), vec!["gnss.vel_e", "gnss.vel_n"]);
+        assert!(Expr::parse("1 + 2").unwrap().channels().is_empty());
+    }
+}
@@ -0,0 +1,473 @@
+//! Arrow IPC (Feather v2) output, so captures load straight into pandas/polars with
+//! `pyarrow.feather.read_feather()` and the right column types.
+//!
+//! An IPC file only has one schema, so every data set gets its own file next to the one
+//! asked for: `drive.feather` becomes `drive.imu.feather`, `drive.gnss.feather` and so on.
+//! Each has a `time` column (UTC timestamp, ns) and one column per channel of the set in
+//! its native type, with a row per packet and nulls for fields the packet didn't carry.
+//! Derived channels go to `drive.derived.feather` as float64, and fields that couldn't be
+//! decoded to `drive.unknown.feather` as their set, field and raw bytes. Events, like
+//! power changes, go to `drive.events.feather` as text.
+//!
+//! The footer is only written by [`FeatherWriter::finish`], a file from a run that was
+//! killed is left as `.partial` and its record batches can be salvaged with [`recover`].
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+};
+
+use arrow::{
+    array::{
+        ArrayRef, BinaryArray, Float32Array, Float64Array, StringArray, TimestampNanosecondArray,
+        UInt16Array, UInt8Array,
+    },
+    datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit},
+    ipc::{reader::StreamReader, writer::FileWriter},
+    record_batch::RecordBatch,
+};
+
+use crate::{
+    channels::{self, Type, UnknownField},
+    device,
+    partial::{self, PartialFile},
+    Error,
+};
+
+// Arrow IPC files start with this, padded to 8 bytes, and then carry on as an IPC stream
+pub const MAGIC: &[u8; 6] = b"ARROW1";
+const MAGIC_PADDED_LEN: usize = 8;
+
+// Rows buffered per table before a record batch is written
+const BATCH_ROWS: usize = 64 * 1024;
+const TIMEZONE: &str = "UTC";
+const DERIVED_TABLE: &str = "derived";
+const UNKNOWN_TABLE: &str = "unknown";
+const EVENTS_TABLE: &str = "events";
+
+pub struct FeatherWriter {
+    path: PathBuf,
+    derived: Vec<String>,
+    // Created when the first row for them arrives, so sets that aren't streamed get no file
+    tables: BTreeMap<String, Table>,
+}
+
+struct Table {
+    writer: FileWriter<SyncOnFlush>,
+    file: Rc<RefCell<PartialFile>>,
+    schema: SchemaRef,
+    times: Vec<i64>,
+    columns: Vec<Column>,
+}
+
+struct Column {
+    name: String,
+    cells: Cells,
+}
+
+enum Cells {
+    Number(Type, Vec<Option<f64>>),
+    Bytes(Vec<Vec<u8>>),
+    Text(Vec<String>),
+}
+
+/// FileWriter buffers internally and flushes once a batch is written, so syncing on that
+/// flush is the only way to be sure the sync covers the batch.
+struct SyncOnFlush(Rc<RefCell<PartialFile>>);
+
+impl Write for SyncOnFlush {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut file = self.0.borrow_mut();
+        file.flush()?;
+        file.sync().map_err(io::Error::other)
+    }
+}
+
+impl FeatherWriter {
+    /// `derived` names the derived channels, which all share one table.
+    pub fn create<P: AsRef<Path>>(path: P, derived: &[String]) -> Result<Self, Error> {
+        Ok(FeatherWriter {
+            path: path.as_ref().to_path_buf(),
+            derived: derived.to_vec(),
+            tables: BTreeMap::new(),
+        })
+    }
+
+    /// Add the channel values from one packet of data set `set`, received at `time` (unix ns).
+    pub fn write(&mut self, time: u64, set: u8, values: &[(String, f64)]) -> Result<(), Error> {
+        let table = device::DATA_SETS
+            .iter()
+            .find(|s| s.descriptor == set)
+            .map(|s| s.name.to_ascii_lowercase());
+        let (native, derived): (Vec<_>, Vec<_>) = values
+            .iter()
+            .partition(|(name, _)| channels::is_known(name));
+
+        if let Some(table) = table {
+            if !native.is_empty() {
+                let columns = channels::FIELDS
+                    .iter()
+                    .filter(|s| s.set == set)
+                    .flat_map(|s| s.channels.iter())
+                    .map(|(name, kind)| (name.to_string(), *kind))
+                    .collect();
+                self.row(&table, columns, time, &native)?;
+            }
+        }
+
+        if !derived.is_empty() {
+            let columns = self
+                .derived
+                .iter()
+                .map(|n| (n.clone(), Type::F64))
+                .collect();
+            self.row(DERIVED_TABLE, columns, time, &derived)?;
+        }
+        Ok(())
+    }
+
+    /// Add the raw bytes of a field that couldn't be decoded.
+    pub fn write_unknown(&mut self, time: u64, field: &UnknownField) -> Result<(), Error> {
+        if !self.tables.contains_key(UNKNOWN_TABLE) {
+            let columns = vec![
+                Column::number("set", Type::U8),
+                Column::number("field", Type::U8),
+                Column::bytes("data"),
+            ];
+            let path = FeatherWriter::table_path(&self.path, UNKNOWN_TABLE);
+            self.tables
+                .insert(UNKNOWN_TABLE.to_string(), Table::create(&path, columns)?);
+        }
+
+        let table = self.tables.get_mut(UNKNOWN_TABLE).unwrap();
+        table.times.push(time as i64);
+        for column in &mut table.columns {
+            match (&mut column.cells, column.name.as_str()) {
+                (Cells::Number(_, cells), "set") => cells.push(Some(field.set as f64)),
+                (Cells::Number(_, cells), _) => cells.push(Some(field.field as f64)),
+                (Cells::Bytes(cells), _) => cells.push(field.data.clone()),
+                (Cells::Text(_), _) => (),
+            }
+        }
+        table.flush_full()
+    }
+
+    /// Add an event, e.g. a power change, that happened at `time`.
+    pub fn write_event(&mut self, time: u64, event: &str) -> Result<(), Error> {
+        if !self.tables.contains_key(EVENTS_TABLE) {
+            let path = FeatherWriter::table_path(&self.path, EVENTS_TABLE);
+            let table = Table::create(&path, vec![Column::text("event")])?;
+            self.tables.insert(EVENTS_TABLE.to_string(), table);
+        }
+
+        let table = self.tables.get_mut(EVENTS_TABLE).unwrap();
+        table.times.push(time as i64);
+        for column in &mut table.columns {
+            if let Cells::Text(cells) = &mut column.cells {
+                cells.push(event.to_string());
+            }
+        }
+        table.flush_full()
+    }
+
+    /// Write whatever is buffered as record batches.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        for table in self.tables.values_mut() {
+            table.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Finish every table, returning the files written.
+    pub fn finish(self) -> Result<Vec<PathBuf>, Error> {
+        self.tables.into_values().map(Table::finish).collect()
+    }
+
+    /// Where the table `name` of `path` is written, e.g. `drive.imu.feather`.
+    pub fn table_path(path: &Path, name: &str) -> PathBuf {
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let file = match path.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, name, ext.to_string_lossy()),
+            None => format!("{}.{}", stem, name),
+        };
+        path.with_file_name(file)
+    }
+
+    fn row(
+        &mut self,
+        name: &str,
+        columns: Vec<(String, Type)>,
+        time: u64,
+        values: &[&(String, f64)],
+    ) -> Result<(), Error> {
+        if !self.tables.contains_key(name) {
+            let columns = columns
+                .into_iter()
+                .map(|(name, kind)| Column::number(&name, kind))
+                .collect();
+            let table = Table::create(&FeatherWriter::table_path(&self.path, name), columns)?;
+            self.tables.insert(name.to_string(), table);
+        }
+
+        let table = self.tables.get_mut(name).unwrap();
+        table.times.push(time as i64);
+        for column in &mut table.columns {
+            let value = values.iter().find(|(n, _)| *n == column.name);
+            if let Cells::Number(_, cells) = &mut column.cells {
+                cells.push(value.map(|(_, v)| *v));
+            }
+        }
+        table.flush_full()
+    }
+}
+
+impl Column {
+    fn number(name: &str, kind: Type) -> Self {
+        Column {
+            name: name.to_string(),
+            cells: Cells::Number(kind, Vec::new()),
+        }
+    }
+
+    fn bytes(name: &str) -> Self {
+        Column {
+            name: name.to_string(),
+            cells: Cells::Bytes(Vec::new()),
+        }
+    }
+
+    fn text(name: &str) -> Self {
+        Column {
+            name: name.to_string(),
+            cells: Cells::Text(Vec::new()),
+        }
+    }
+
+    fn field(&self) -> Field {
+        match &self.cells {
+            Cells::Number(kind, _) => Field::new(&self.name, data_type(*kind), true),
+            Cells::Bytes(_) => Field::new(&self.name, DataType::Binary, false),
+            Cells::Text(_) => Field::new(&self.name, DataType::Utf8, false),
+        }
+    }
+
+    /// Everything buffered as an array, leaving the column empty.
+    fn take(&mut self) -> ArrayRef {
+        match &mut self.cells {
+            Cells::Number(kind, cells) => array(*kind, cells.split_off(0)),
+            Cells::Bytes(cells) => {
+                let cells = cells.split_off(0);
+                Arc::new(BinaryArray::from(
+                    cells.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+                ))
+            }
+            Cells::Text(cells) => {
+                let cells = cells.split_off(0);
+                Arc::new(StringArray::from(
+                    cells.iter().map(String::as_str).collect::<Vec<_>>(),
+                ))
+            }
+        }
+    }
+}
+
+impl Table {
+    fn create(path: &Path, columns: Vec<Column>) -> Result<Self, Error> {
+        let mut fields = vec![Field::new(
+            "time",
+            DataType::Timestamp(TimeUnit::Nanosecond, Some(TIMEZONE.to_string())),
+            false,
+        )];
+        fields.extend(columns.iter().map(Column::field));
+        let schema = Arc::new(Schema::new(fields));
+
+        let file = Rc::new(RefCell::new(PartialFile::create(path)?));
+        let writer = FileWriter::try_new(SyncOnFlush(file.clone()), &schema)?;
+
+        Ok(Table {
+            writer,
+            file,
+            schema,
+            times: Vec::new(),
+            columns,
+        })
+    }
+
+    fn flush_full(&mut self) -> Result<(), Error> {
+        if self.times.len() >= BATCH_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.times.is_empty() {
+            return Ok(());
+        }
+
+        let mut arrays: Vec<ArrayRef> = vec![Arc::new(TimestampNanosecondArray::from_vec(
+            self.times.split_off(0),
+            Some(TIMEZONE.to_string()),
+        ))];
+        arrays.extend(self.columns.iter_mut().map(Column::take));
+
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays)?;
+        self.writer.write(&batch)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<PathBuf, Error> {
+        self.flush()?;
+        self.writer.finish()?;
+
+        // The writer holds the only other reference
+        drop(self.writer);
+        let file = Rc::try_unwrap(self.file).map_err(|_| "Feather file is still in use")?;
+        file.into_inner().commit()
+    }
+}
+
+fn data_type(kind: Type) -> DataType {
+    match kind {
+        Type::U8 => DataType::UInt8,
+        Type::U16 => DataType::UInt16,
+        Type::F32 => DataType::Float32,
+        Type::F64 => DataType::Float64,
+    }
+}
+
+// Values were decoded from these types, so the casts back are exact
+fn array(kind: Type, cells: Vec<Option<f64>>) -> ArrayRef {
+    let cells = cells.into_iter();
+    match kind {
+        Type::U8 => Arc::new(UInt8Array::from(
+            cells.map(|c| c.map(|v| v as u8)).collect::<Vec<_>>(),
+        )),
+        Type::U16 => Arc::new(UInt16Array::from(
+            cells.map(|c| c.map(|v| v as u16)).collect::<Vec<_>>(),
+        )),
+        Type::F32 => Arc::new(Float32Array::from(
+            cells.map(|c| c.map(|v| v as f32)).collect::<Vec<_>>(),
+        )),
+        Type::F64 => Arc::new(Float64Array::from(cells.collect::<Vec<_>>())),
+    }
+}
+
+/// Copy every whole record batch from a file that was never finished into a new one at
+/// `output`. Returns the number of rows saved.
+pub fn recover<P: AsRef<Path>>(input: &Path, output: P) -> Result<usize, Error> {
+    if partial::writes_over(output.as_ref(), input) {
+        return Err(format!("Recovering {} would write over it", input.display()).into());
+    }
+    let mut reader = BufReader::new(File::open(input)?);
+    let mut magic = [0u8; MAGIC_PADDED_LEN];
+    reader.read_exact(&mut magic)?;
+    if &magic[..MAGIC.len()] != MAGIC {
+        return Err(format!("{} is not an Arrow IPC file", input.display()).into());
+    }
+
+    let batches = StreamReader::try_new(reader)
+        .map_err(|e| format!("{} has no schema to recover: {}", input.display(), e))?;
+    let file = PartialFile::create(output)?;
+    let mut writer = FileWriter::try_new(file.try_clone()?, &batches.schema())?;
+
+    let mut rows = 0;
+    // The batch being written when the run died is cut short, stop there
+    for batch in batches.take_while(Result::is_ok).flatten() {
+        writer.write(&batch)?;
+        rows += batch.num_rows();
+    }
+
+    writer.finish()?;
+    file.commit()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use arrow::ipc::reader::FileReader;
+
+    use super::*;
+    use crate::mip;
+
+    fn read(path: &Path) -> RecordBatch {
+        let reader = FileReader::try_new(File::open(path).unwrap()).unwrap();
+        let batches: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1);
+        batches.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn tables_per_set_with_native_types() {
+        let dir = env::temp_dir().join(format!("lordcli-feather-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("drive.feather");
+
+        let mut writer = FeatherWriter::create(&path, &["speed".to_string()]).unwrap();
+        let values = vec![
+            ("imu.accel_z".to_string(), -1.0),
+            ("speed".to_string(), 2.5),
+        ];
+        writer.write(10, mip::IMU_DATA, &values).unwrap();
+        writer.write(20, mip::IMU_DATA, &values[..1]).unwrap();
+        let unknown = UnknownField {
+            set: mip::IMU_DATA,
+            field: 0x99,
+            data: vec![1, 2, 3],
+        };
+        writer.write_unknown(20, &unknown).unwrap();
+        writer.write_event(30, "power state=battery").unwrap();
+
+        let mut paths = writer.finish().unwrap();
+        paths.sort();
+        let names = [
+            "drive.derived.feather",
+            "drive.events.feather",
+            "drive.imu.feather",
+            "drive.unknown.feather",
+        ];
+        assert_eq!(paths, names.iter().map(|n| dir.join(n)).collect::<Vec<_>>());
+
+        let imu = read(&paths[2]);
+        assert_eq!(imu.num_rows(), 2);
+        let accel_z = imu.schema().index_of("imu.accel_z").unwrap();
+        assert_eq!(imu.schema().field(accel_z).data_type(), &DataType::Float32);
+        let accel_x = imu.column(imu.schema().index_of("imu.accel_x").unwrap());
+        assert_eq!(accel_x.null_count(), 2);
+
+        let derived = read(&paths[0]);
+        assert_eq!(derived.num_rows(), 1);
+
+        let unknown = read(&paths[3]);
+        let data = unknown
+            .column(3)
+            .as_any()
+            .downcast_ref::<BinaryArray>()
+            .unwrap();
+        assert_eq!(data.value(0), &[1, 2, 3]);
+
+        let events = read(&paths[1]);
+        let event = events
+            .column(1)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(event.value(0), "power state=battery");
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
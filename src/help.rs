@@ -0,0 +1,566 @@
+//! Long `--help` text for every subcommand, built from the same command, data set, field and
+//! profile tables the code uses so it can't drift from what is actually sent.
+//!
+//! When `LORDCLI_HELP_PORT` names a port, the device on it is asked for its model and base
+//! rates so the ranges shown are the real ones for that unit. Help is printed before the
+//! command line is parsed, so asking for this has to be explicit.
+
+use std::{env, fmt::Write as _, sync::Arc, time::Duration};
+
+use clap::App;
+
+use crate::{
+    aid, channels,
+    clock::SystemClock,
+    device::{self, DataSet, DeviceInfo},
+    discover, lock,
+    mip::{self, Command},
+    profile, record,
+    transport::{self, Pacing},
+    uplink,
+};
+
+const DETECT_TIMEOUT: Duration = Duration::from_millis(250);
+const PORT_VARIABLE: &str = "LORDCLI_HELP_PORT";
+// Neither --config nor the pacing options are parsed yet, so go gently
+const DETECT_PACING: Pacing = Pacing {
+    command_delay: Duration::from_millis(20),
+    write_chunk: 0,
+    chunk_delay: Duration::from_millis(0),
+};
+const EXAMPLE_PORT: &str = "/dev/ttyACM0";
+
+pub struct Entry {
+    /// Subcommand names from the top level, space separated
+    pub path: &'static str,
+    pub about: &'static str,
+    /// MIP commands the subcommand sends, from the tables it sends them from
+    pub commands: fn() -> Vec<Command>,
+    /// Whether it sets message formats, so the data fields and rate ranges matter
+    pub formats: bool,
+    /// Whether it takes channel names or expressions over them
+    pub channels: bool,
+    /// `{port}` is replaced with the port being used
+    pub examples: &'static [&'static str],
+}
+
+fn no_commands() -> Vec<Command> {
+    Vec::new()
+}
+
+fn rate_commands() -> Vec<Command> {
+    profile::RATE.iter().map(|set| set.base_rate).collect()
+}
+
+fn configure_commands() -> Vec<Command> {
+    profile::CONFIGURE
+        .iter()
+        .map(|(set, _)| set.format)
+        .collect()
+}
+
+fn ekf_commands() -> Vec<Command> {
+    let mut commands: Vec<Command> = profile::EKF.iter().map(|(set, _)| set.format).collect();
+    commands.push(mip::FILTER_AUTO_INIT);
+    commands
+}
+
+fn packet_commands() -> Vec<Command> {
+    profile::PACKET
+        .iter()
+        .map(|(command, _)| *command)
+        .collect()
+}
+
+pub const ENTRIES: &[Entry] = &[
+    Entry {
+        path: "test",
+        about: "Test the IMU",
+        commands: no_commands,
+        formats: false,
+        channels: false,
+        examples: &["lordcli {port} test", "lordcli {port} test soak --hours 24"],
+    },
+    Entry {
+        path: "test soak",
+        about: "Stream for hours, writing periodic snapshots to a report and failing on rate drops, reconnects or filter divergence",
+        commands: device::commands,
+        formats: false,
+        channels: false,
+        examples: &[
+            "lordcli {port} test soak --hours 24 --snapshot-interval 10m",
+            "lordcli {port} test soak --hours 0.5 --rate-tolerance 2 --report bench.log",
+        ],
+    },
+    Entry {
+        path: "configure",
+        about: "Configure the IMU",
+        commands: configure_commands,
+        formats: true,
+        channels: false,
+        examples: &["lordcli {port} configure"],
+    },
+    Entry {
+        path: "read",
+        about: "Stream data",
+        commands: no_commands,
+        formats: false,
+        channels: false,
+        examples: &["lordcli {port} read --time-format tow-week"],
+    },
+    Entry {
+        path: "monitor",
+        about: "Watch channels and raise alarms when they cross thresholds",
+        commands: no_commands,
+        formats: false,
+        channels: true,
+        examples: &[
+            "lordcli {port} monitor --channel filter.yaw",
+            "lordcli {port} monitor --alarm \"abs(filter.roll) > 30deg\" --beep",
+        ],
+    },
+    Entry {
+        path: "record",
+        about: "Record data to a capture file",
+        commands: || record::COMMANDS.to_vec(),
+        formats: false,
+        channels: false,
+        examples: &[
+            "lordcli {port} record drive.cap",
+            "lordcli {port} record drive.feather",
+            "lordcli {port} record ring.bin --blackbox --size 512MB --trigger-file /tmp/freeze",
+            "lordcli {port} record drive.cap --power-gpio 17 --power-shutdown",
+            "lordcli {port} record drive.cap --uplink https://example.com/telemetry --uplink-interval 15min",
+        ],
+    },
+    Entry {
+        path: "uplink",
+        about: "Post a compact JSON health and position summary over HTTP at a low rate",
+        commands: || uplink::COMMANDS.to_vec(),
+        formats: false,
+        channels: false,
+        examples: &[
+            "lordcli {port} uplink --http https://example.com/telemetry --interval 60s",
+            "lordcli {port} uplink --http https://example.com/telemetry --interval 15min --config site.toml",
+        ],
+    },
+    Entry {
+        path: "aid",
+        about: "Feed measurements from other sensors to the filter as external aiding",
+        commands: aid::commands,
+        formats: false,
+        channels: false,
+        examples: &["lordcli {port} aid ascii --port /dev/ttyUSB2 --parser '$VEL,{speed}'"],
+    },
+    Entry {
+        path: "aid ascii",
+        about: "Parse line based ASCII telemetry from a serial port",
+        commands: aid::commands,
+        formats: false,
+        channels: false,
+        examples: &[
+            "lordcli {port} aid ascii --port /dev/ttyUSB2 --parser '$VEL,{speed}'",
+            "lordcli {port} aid ascii --port /dev/ttyUSB2 --baud 115200 --parser 'HDG {heading} {heading_uncertainty}'",
+        ],
+    },
+    Entry {
+        path: "mission",
+        about: "Run multi stage field protocols",
+        commands: device::commands,
+        formats: true,
+        channels: true,
+        examples: &["lordcli {port} mission run survey.yaml"],
+    },
+    Entry {
+        path: "mission run",
+        about: "Run each phase of a mission profile in turn",
+        commands: device::commands,
+        formats: true,
+        channels: true,
+        examples: &["lordcli {port} mission run survey.yaml"],
+    },
+    Entry {
+        path: "convert",
+        about: "Convert a capture file for use in other tools",
+        commands: no_commands,
+        formats: false,
+        channels: true,
+        examples: &[
+            "lordcli convert drive.cap drive.mat",
+            "lordcli convert drive.cap drive.feather",
+            "lordcli convert drive.cap drive.txt --to csv --time-format unix",
+        ],
+    },
+    Entry {
+        path: "config",
+        about: "Check the saved startup settings",
+        commands: device::commands,
+        formats: true,
+        channels: false,
+        examples: &[
+            "lordcli {port} config dump golden.toml",
+            "lordcli {port} config audit --baseline golden.toml",
+        ],
+    },
+    Entry {
+        path: "config dump",
+        about: "Write the startup settings as a TOML baseline",
+        commands: device::commands,
+        formats: true,
+        channels: false,
+        examples: &["lordcli {port} config dump golden.toml"],
+    },
+    Entry {
+        path: "config audit",
+        about: "Compare the startup settings to a baseline, failing if they differ",
+        commands: device::commands,
+        formats: true,
+        channels: false,
+        examples: &["lordcli {port} config audit --baseline golden.toml"],
+    },
+    Entry {
+        path: "stats",
+        about: "Summarize the packets, events and undecodable fields in a capture file",
+        commands: no_commands,
+        formats: false,
+        channels: false,
+        examples: &["lordcli stats drive.cap", "lordcli stats drive.cap --strict"],
+    },
+    Entry {
+        path: "recover",
+        about: "Salvage the data from capture and feather files left by a crash or power loss",
+        commands: no_commands,
+        formats: false,
+        channels: false,
+        examples: &[
+            "lordcli recover drive.cap.partial",
+            "lordcli recover drive.imu.feather.partial salvaged.feather",
+        ],
+    },
+    Entry {
+        path: "list",
+        about: "List USB Devices",
+        commands: no_commands,
+        formats: false,
+        channels: false,
+        examples: &["lordcli {port} list"],
+    },
+    Entry {
+        path: "discover",
+        about: "Find serial ports with a responsive IMU attached",
+        commands: || discover::COMMANDS.to_vec(),
+        formats: false,
+        channels: false,
+        examples: &["lordcli discover", "lordcli discover --scan-bauds"],
+    },
+    Entry {
+        path: "rate",
+        about: "Print the IMU and GNSS base rates",
+        commands: rate_commands,
+        formats: false,
+        channels: false,
+        examples: &["lordcli {port} rate"],
+    },
+    Entry {
+        path: "packet",
+        about: "Apply and save a fixed IMU, GNSS and filter configuration",
+        commands: packet_commands,
+        formats: true,
+        channels: false,
+        examples: &["lordcli {port} packet"],
+    },
+    Entry {
+        path: "ekf",
+        about: "Configure filter and GNSS output and enable filter auto-initialization",
+        commands: ekf_commands,
+        formats: true,
+        channels: false,
+        examples: &["lordcli {port} ekf"],
+    },
+    Entry {
+        path: "quickstart",
+        about: "Detect the IMU, apply a default configuration and check data is flowing",
+        commands: device::commands,
+        formats: true,
+        channels: false,
+        examples: &[
+            "lordcli quickstart",
+            "lordcli {port} quickstart",
+            "lordcli {port} quickstart --duration 10 --save",
+        ],
+    },
+];
+
+/// What was learned from the device on PORT, if there is one.
+pub struct Detected {
+    pub port: String,
+    pub info: DeviceInfo,
+    pub base_rates: Vec<(DataSet, u16)>,
+}
+
+/// Long help for every entry, built once before the clap definitions borrow from it.
+pub struct LongHelp {
+    texts: Vec<(&'static str, String)>,
+}
+
+impl LongHelp {
+    pub fn new(detected: Option<Detected>) -> Self {
+        LongHelp {
+            texts: ENTRIES
+                .iter()
+                .map(|entry| (entry.path, long_help(entry, detected.as_ref())))
+                .collect(),
+        }
+    }
+
+    /// A subcommand with its short and long help filled in.
+    pub fn app(&self, path: &str) -> App<'_> {
+        let entry = entry(path);
+        let name = path.rsplit(' ').next().unwrap_or(path);
+        let long = self
+            .texts
+            .iter()
+            .find(|(p, _)| *p == path)
+            .map_or("", |(_, text)| text.as_str());
+
+        App::new(name).about(entry.about).long_about(long)
+    }
+}
+
+fn entry(path: &str) -> &'static Entry {
+    ENTRIES
+        .iter()
+        .find(|e| e.path == path)
+        .unwrap_or_else(|| panic!("No help entry for {}", path))
+}
+
+fn long_help(entry: &Entry, detected: Option<&Detected>) -> String {
+    let mut text = String::new();
+    // Writing to a String can't fail
+    let _ = write_long_help(&mut text, entry, detected);
+    text
+}
+
+fn write_long_help(
+    text: &mut String,
+    entry: &Entry,
+    detected: Option<&Detected>,
+) -> std::fmt::Result {
+    writeln!(text, "{}", entry.about)?;
+
+    let mut commands = (entry.commands)();
+    commands.sort_by_key(|c| (c.set, c.field));
+    commands.dedup();
+    if !commands.is_empty() {
+        writeln!(text)?;
+        writeln!(text, "MIP commands (descriptor set/field):")?;
+        for command in commands {
+            writeln!(
+                text,
+                "    0x{:02X}/0x{:02X}  {}",
+                command.set,
+                command.field,
+                mip::command_name(command)
+            )?;
+        }
+    }
+
+    if entry.formats {
+        writeln!(text)?;
+        write_ranges(text, detected)?;
+
+        writeln!(text)?;
+        writeln!(text, "Data fields:")?;
+        for set in device::DATA_SETS.iter() {
+            for spec in channels::FIELDS.iter().filter(|s| s.set == set.descriptor) {
+                writeln!(
+                    text,
+                    "    0x{:02X}/0x{:02X}  {} {}",
+                    spec.set, spec.field, set.name, spec.description
+                )?;
+            }
+        }
+    }
+
+    if entry.channels {
+        writeln!(text)?;
+        writeln!(text, "Channels:")?;
+        for spec in channels::FIELDS {
+            let names: Vec<&str> = spec.channels.iter().map(|(name, _)| *name).collect();
+            writeln!(text, "    {}  ({})", names.join(" "), spec.description)?;
+        }
+        writeln!(
+            text,
+            "    Derived channels can be defined in the [channels] section of --config"
+        )?;
+    }
+
+    if !entry.examples.is_empty() {
+        let port = detected.map_or(EXAMPLE_PORT, |d| d.port.as_str());
+        writeln!(text)?;
+        writeln!(text, "Examples:")?;
+        for example in entry.examples {
+            writeln!(text, "    {}", example.replace("{port}", port))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_ranges(text: &mut String, detected: Option<&Detected>) -> std::fmt::Result {
+    let detected = match detected {
+        Some(detected) => detected,
+        None => {
+            writeln!(
+                text,
+                "Rates (set {} to a port for the ranges of your device):",
+                PORT_VARIABLE
+            )?;
+            for format in profile::DEFAULT_FORMATS {
+                if let Some(set) = device::DATA_SETS
+                    .iter()
+                    .find(|s| s.descriptor == format.set)
+                {
+                    writeln!(
+                        text,
+                        "    {:<7} 1 Hz up to the device's base rate, {} Hz by default",
+                        set.name, format.rate
+                    )?;
+                }
+            }
+            return Ok(());
+        }
+    };
+
+    let profile = profile::for_model(&detected.info.model_name);
+    writeln!(
+        text,
+        "Rates for {} ({} profile):",
+        detected.info.model_name, profile.name
+    )?;
+
+    for set in device::DATA_SETS.iter() {
+        let base_rate = detected
+            .base_rates
+            .iter()
+            .find(|(s, _)| s == set)
+            .map(|(_, rate)| *rate);
+        let default = profile.formats.iter().find(|f| f.set == set.descriptor);
+
+        match (base_rate, default) {
+            (Some(base_rate), Some(default)) => writeln!(
+                text,
+                "    {:<7} 1-{} Hz (decimation 1-{}), {} Hz by default",
+                set.name, base_rate, base_rate, default.rate
+            )?,
+            (Some(base_rate), None) => writeln!(
+                text,
+                "    {:<7} 1-{} Hz (decimation 1-{})",
+                set.name, base_rate, base_rate
+            )?,
+            (None, _) => writeln!(text, "    {:<7} not available", set.name)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// If long help was asked for and `LORDCLI_HELP_PORT` is set, query the device on that port.
+pub fn detect() -> Option<Detected> {
+    if !help_requested(&env::args().collect::<Vec<_>>()) {
+        return None;
+    }
+    let port = env::var(PORT_VARIABLE).ok()?;
+
+    // Held until the device has answered, never stolen just to print help
+    let _lock = lock::PortLock::acquire(&port, false).ok()?;
+    let clock = Arc::new(SystemClock::new());
+    let serial = transport::open(&port, clock.clone()).ok()?;
+    let mut serial = transport::pace(serial, DETECT_PACING, clock.clone());
+    transport::resync(&mut *serial, &*clock).ok()?;
+    serial.set_timeout(Duration::from_millis(10)).ok()?;
+
+    let info = device::query_info(&mut *serial, &*clock, DETECT_TIMEOUT).ok()?;
+
+    let mut base_rates = Vec::new();
+    for set in device::DATA_SETS.iter() {
+        let reply = device::send_raw(&mut *serial, &*clock, set.base_rate, vec![], DETECT_TIMEOUT);
+        if let Ok(rate) = reply.and_then(|r| device::parse_base_rate(&r, *set)) {
+            base_rates.push((*set, rate));
+        }
+    }
+
+    Some(Detected {
+        port,
+        info,
+        base_rates,
+    })
+}
+
+/// Whether `args` ask for help, with `--help`/`-h` or with `help` where the subcommand goes.
+/// A file or port that happens to be called `help` doesn't count.
+fn help_requested(args: &[String]) -> bool {
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        return true;
+    }
+
+    // Subcommand names can't be option values or ports, so the first one is the subcommand
+    args.iter()
+        .skip(1)
+        .find(|a| *a == "help" || ENTRIES.iter().any(|e| e.path == a.as_str()))
+        .is_some_and(|a| a == "help")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(path: &str) -> Vec<Command> {
+        (entry(path).commands)()
+    }
+
+    #[test]
+    fn entries_list_what_their_modules_send() {
+        assert_eq!(commands("record"), record::COMMANDS);
+        assert_eq!(commands("uplink"), uplink::COMMANDS);
+        assert_eq!(commands("discover"), discover::COMMANDS);
+        assert_eq!(commands("aid ascii"), aid::commands());
+        for path in &["test soak", "mission run", "config audit", "quickstart"] {
+            assert_eq!(commands(path), device::commands());
+        }
+
+        let rates: Vec<Command> = profile::RATE.iter().map(|s| s.base_rate).collect();
+        assert_eq!(commands("rate"), rates);
+        let formats: Vec<Command> = profile::CONFIGURE.iter().map(|(s, _)| s.format).collect();
+        assert_eq!(commands("configure"), formats);
+        assert!(commands("ekf").contains(&mip::FILTER_AUTO_INIT));
+        assert_eq!(commands("packet").len(), profile::PACKET.len());
+    }
+
+    #[test]
+    fn examples_run_their_own_command() {
+        for entry in ENTRIES {
+            for example in entry.examples {
+                assert!(example.contains(entry.path), "{}: {}", entry.path, example);
+            }
+        }
+    }
+
+    #[test]
+    fn help_is_only_asked_for_where_the_subcommand_goes() {
+        let args = |line: &str| -> Vec<String> { line.split(' ').map(String::from).collect() };
+
+        assert!(help_requested(&args("lordcli help")));
+        assert!(help_requested(&args("lordcli help record")));
+        assert!(help_requested(&args("lordcli /dev/ttyACM0 help")));
+        assert!(help_requested(&args("lordcli /dev/ttyACM0 record --help")));
+        assert!(help_requested(&args("lordcli convert -h")));
+
+        assert!(!help_requested(&args("lordcli convert help out.csv")));
+        assert!(!help_requested(&args("lordcli /dev/ttyACM0 record help")));
+        assert!(!help_requested(&args(
+            "lordcli /dev/ttyACM0 mission run help"
+        )));
+        assert!(!help_requested(&args("lordcli /dev/ttyACM0 read")));
+    }
+}
@@ -0,0 +1,213 @@
+//! Advisory lock so two lordcli instances don't read from the same port at once, which
+//! splits the byte stream between them and garbles both.
+//!
+//! The lock is a file in the temp directory named after the port, holding the pid,
+//! command line and start time of the owner for diagnostics. It's written in full under a
+//! temporary name and hard linked into place, so it never exists half written. A lock whose
+//! owner has exited without cleaning up is taken over automatically.
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{capture, Error};
+
+pub struct PortLock {
+    path: PathBuf,
+}
+
+/// Whoever currently holds a port's lock.
+#[derive(Debug, Clone)]
+pub struct Holder {
+    pub pid: u32,
+    pub command: String,
+    pub since: u64,
+}
+
+impl Holder {
+    fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.lines();
+        Some(Holder {
+            pid: lines.next()?.trim().parse().ok()?,
+            command: lines.next().unwrap_or("").to_string(),
+            since: lines
+                .next()
+                .and_then(|l| l.trim().parse().ok())
+                .unwrap_or(0),
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn is_running(&self) -> bool {
+        Path::new(&format!("/proc/{}", self.pid)).exists()
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn is_running(&self) -> bool {
+        // Signal 0 only checks the process exists, a permission error means it does
+        match process::Command::new("kill")
+            .args(&["-0", &self.pid.to_string()])
+            .output()
+        {
+            Ok(output) => {
+                output.status.success()
+                    || String::from_utf8_lossy(&output.stderr).contains("not permitted")
+            }
+            Err(_) => true,
+        }
+    }
+
+    #[cfg(windows)]
+    fn is_running(&self) -> bool {
+        match process::Command::new("tasklist")
+            .args(&["/FI", &format!("PID eq {}", self.pid), "/NH"])
+            .output()
+        {
+            Ok(output) => String::from_utf8_lossy(&output.stdout).contains(&self.pid.to_string()),
+            Err(_) => true,
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn is_running(&self) -> bool {
+        // No way to check, assume the worst and let --steal sort it out
+        true
+    }
+}
+
+impl PortLock {
+    /// Take the lock for `port_name`. Fails if another running instance has it, unless
+    /// `steal` is set.
+    pub fn acquire(port_name: &str, steal: bool) -> Result<Self, Error> {
+        let path = lock_path(port_name);
+        let contents = format!(
+            "{}\n{}\n{}\n",
+            process::id(),
+            env::args().collect::<Vec<_>>().join(" "),
+            capture::timestamp() / 1_000_000_000
+        );
+
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(format!(".{}", process::id()));
+        let temporary = PathBuf::from(temporary);
+        fs::write(&temporary, contents)?;
+        let acquired = PortLock::link(&temporary, &path, port_name, steal);
+        fs::remove_file(&temporary).ok();
+        acquired
+    }
+
+    fn link(temporary: &Path, path: &Path, port_name: &str, steal: bool) -> Result<Self, Error> {
+        loop {
+            // Fails if the lock exists, so only one instance can win
+            match fs::hard_link(temporary, path) {
+                Ok(()) => {
+                    return Ok(PortLock {
+                        path: path.to_path_buf(),
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e.into()),
+            }
+
+            let current = fs::read_to_string(path)
+                .ok()
+                .and_then(|c| Holder::parse(&c))
+                .filter(|h| h.pid != process::id() && h.is_running());
+            if let Some(holder) = current {
+                if !steal {
+                    return Err(in_use(port_name, &holder).into());
+                }
+                eprintln!("Warning: taking {} from pid {}", port_name, holder.pid);
+            }
+
+            // Stale, or being stolen. Removing it can race another instance doing the
+            // same, in which case the create above fails again and we look again.
+            match fs::remove_file(path) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for PortLock {
+    fn drop(&mut self) {
+        // Leave it alone if someone stole it from us
+        let ours = fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|c| Holder::parse(&c))
+            .map(|h| h.pid)
+            == Some(process::id());
+
+        if ours {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// The instance holding `port_name`, if any.
+pub fn holder(port_name: &str) -> Option<Holder> {
+    let holder = Holder::parse(&fs::read_to_string(lock_path(port_name)).ok()?)?;
+    Some(holder).filter(Holder::is_running)
+}
+
+fn in_use(port_name: &str, holder: &Holder) -> String {
+    format!(
+        "{} is in use by another lordcli (pid {}, started {}s ago): {}\nStop it first, or use --steal to take the port anyway",
+        port_name,
+        holder.pid,
+        (capture::timestamp() / 1_000_000_000).saturating_sub(holder.since),
+        holder.command
+    )
+}
+
+/// Keyed on the real device, so `/dev/serial/by-id/...` and the tty it points at share one.
+fn lock_path(port_name: &str) -> PathBuf {
+    let port = fs::canonicalize(port_name)
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_else(|_| port_name.to_string());
+    let name: String = port
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    env::temp_dir().join(format!("lordcli-{}.lock", name))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn held_stale_and_aliased_locks() {
+        let dir = env::temp_dir().join(format!("lordcli-lock-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let port = dir.join("ttyTEST0");
+        fs::write(&port, "").unwrap();
+        let alias = dir.join("by-id");
+        symlink(&port, &alias).unwrap();
+        let port = port.to_str().unwrap();
+        let alias = alias.to_str().unwrap();
+        assert_eq!(lock_path(port), lock_path(alias));
+
+        // pid 1 is always running
+        fs::write(lock_path(port), "1\ninit\n0\n").unwrap();
+        assert!(PortLock::acquire(alias, false).is_err());
+        assert_eq!(holder(port).unwrap().pid, 1);
+
+        // Nothing runs with the largest pid
+        fs::write(lock_path(port), format!("{}\ngone\n0\n", u32::MAX)).unwrap();
+        assert!(holder(port).is_none());
+        let lock = PortLock::acquire(alias, false).unwrap();
+        assert_eq!(holder(port).unwrap().pid, process::id());
+
+        drop(lock);
+        assert!(!lock_path(port).exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,13 +1,53 @@
-use std::{collections::HashMap, time::Instant};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
 
 use clap::{crate_version, App, AppSettings, Arg};
 use desert::ToBytes;
 use lordserial::{Field, Packet, parser::Lord};
-use serialport;
+
+use clock::{Clock, SystemClock};
+
+mod aid;
+mod blackbox;
+mod capture;
+mod channels;
+mod clock;
+mod config;
+mod convert;
+mod derived;
+mod device;
+mod discover;
+mod expr;
+mod feather;
+mod help;
+mod lock;
+mod mat;
+mod mip;
+mod mission;
+mod monitor;
+mod partial;
+mod power;
+mod profile;
+mod quickstart;
+mod record;
+mod recover;
+mod settings;
+mod soak;
+mod stats;
+mod time;
+mod transport;
+mod uplink;
+#[cfg(feature = "virtual")]
+mod virtual_port;
 
 type Error = Box<dyn std::error::Error + Sync + Send>;
 
 fn main() -> Result<(), Error> {
+    let help = help::LongHelp::new(help::detect());
     let matches = App::new("Lord CLI Utility")
         .version(crate_version!())
         .author("Davis Schenkenberger <davis13@colostate.edu>")
@@ -15,153 +55,504 @@ fn main() -> Result<(), Error> {
         .setting(AppSettings::ArgRequiredElseHelp)
         .arg(
             Arg::new("PORT")
-                .about("The serial port to use")
+                .about("The serial port to use, not needed by discover, convert or stats and found by quickstart if left out")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("steal")
+                .long("steal")
+                .about("Use the port even if another lordcli instance has it open")
+                .global(true),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .about("Fail on data fields that can't be decoded instead of reporting and skipping them")
+                .global(true),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .about("TOML settings file, e.g. for per model write pacing")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("command-delay")
+                .long("command-delay")
+                .about("Minimum time between commands, e.g. 20ms")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("write-chunk")
+                .long("write-chunk")
+                .about("Largest number of bytes written to the port at once, 0 for no limit")
                 .takes_value(true)
-                .required(true),
-        )
-        .subcommand(App::new("test").about("Test the IMU"))
-        .subcommand(App::new("configure").about("Configure the IMU"))
-        .subcommand(App::new("read").about("Stream data"))
-        .subcommand(App::new("list").about("List USB Devices"))
-        .subcommand(App::new("rate"))
-        .subcommand(App::new("packet"))
-        .subcommand(App::new("ekf"))
+                .global(true),
+        )
+        .arg(
+            Arg::new("chunk-delay")
+                .long("chunk-delay")
+                .about("Time between the chunks of a command split by --write-chunk, e.g. 2ms")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("time-format")
+                .long("time-format")
+                .about("How timestamps are written to the console, logs and csv files. mat keeps unix seconds and feather keeps UTC nanosecond timestamps so they stay machine readable")
+                .takes_value(true)
+                .possible_values(time::TIME_FORMATS)
+                .default_value("iso8601")
+                .global(true),
+        )
+        .arg(
+            Arg::new("timezone")
+                .long("timezone")
+                .about("Timezone for iso8601 timestamps: UTC, local or a name like America/Denver")
+                .takes_value(true)
+                .default_value("UTC")
+                .global(true),
+        )
+        .subcommand(
+            help.app("test")
+                .subcommand(
+                    help.app("test soak")
+                        .arg(
+                            Arg::new("hours")
+                                .long("hours")
+                                .about("How long to run for")
+                                .takes_value(true)
+                                .default_value("24"),
+                        )
+                        .arg(
+                            Arg::new("snapshot-interval")
+                                .long("snapshot-interval")
+                                .about("How often to write rates and health to the report, e.g. 10m")
+                                .takes_value(true)
+                                .default_value("10m"),
+                        )
+                        .arg(
+                            Arg::new("stall-timeout")
+                                .long("stall-timeout")
+                                .about("Gap in the data that counts as the device dropping out")
+                                .takes_value(true)
+                                .default_value("2s"),
+                        )
+                        .arg(
+                            Arg::new("rate-tolerance")
+                                .long("rate-tolerance")
+                                .about("Percent a data set's rate can fall below what it's configured for before failing")
+                                .takes_value(true)
+                                .default_value("5"),
+                        )
+                        .arg(
+                            Arg::new("report")
+                                .long("report")
+                                .about("File the snapshots and failures are written to")
+                                .takes_value(true)
+                                .default_value("soak-report.log"),
+                        ),
+                ),
+        )
+        .subcommand(help.app("configure"))
+        .subcommand(help.app("read"))
+        .subcommand(
+            help.app("monitor")
+                .arg(
+                    Arg::new("alarm")
+                        .long("alarm")
+                        .about("Expression that raises an alarm while true, e.g. \"abs(filter.roll) > 30deg\"")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::new("channel")
+                        .long("channel")
+                        .about("Extra channel to display, e.g. filter.yaw")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::new("beep")
+                        .long("beep")
+                        .about("Ring the terminal bell while an alarm is active"),
+                )
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .about("Milliseconds between status lines")
+                        .takes_value(true)
+                        .default_value("1000"),
+                ),
+        )
+        .subcommand(
+            help.app("record")
+                .arg(
+                    Arg::new("OUTPUT")
+                        .about("Capture file to write, or the ring file with --blackbox")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .about("Raw MIP capture, or decoded channels as one Arrow IPC/Feather file per data set with events such as power changes in a .events file. Defaults to feather for .feather/.arrow files")
+                        .takes_value(true)
+                        .possible_values(record::FORMATS),
+                )
+                .arg(
+                    Arg::new("blackbox")
+                        .long("blackbox")
+                        .about("Record continuously into a fixed size ring, freezing a copy when triggered"),
+                )
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .about("Size of the black box ring, e.g. 512MB")
+                        .takes_value(true)
+                        .default_value("512MB"),
+                )
+                .arg(
+                    Arg::new("trigger-file")
+                        .long("trigger-file")
+                        .about("Freeze the black box when this file is created")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("stall-timeout")
+                        .long("stall-timeout")
+                        .about("Freeze the black box when no data arrives for this many seconds, 0 to disable")
+                        .takes_value(true)
+                        .default_value("2"),
+                )
+                .arg(
+                    Arg::new("power-gpio")
+                        .long("power-gpio")
+                        .about("GPIO number or sysfs value file that is high while powered")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("power-active-low")
+                        .long("power-active-low")
+                        .about("Treat the power GPIO as low while powered"),
+                )
+                .arg(
+                    Arg::new("ups")
+                        .long("ups")
+                        .about("Network UPS Tools UPS to watch, as name@host[:port]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("power-interval")
+                        .long("power-interval")
+                        .about("Milliseconds between power checks")
+                        .takes_value(true)
+                        .default_value("500"),
+                )
+                .arg(
+                    Arg::new("power-shutdown")
+                        .long("power-shutdown")
+                        .about("Close the recording cleanly on power loss or low battery"),
+                )
+                .arg(
+                    Arg::new("uplink")
+                        .long("uplink")
+                        .about("URL to POST a compact JSON health and position summary to while recording")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("uplink-interval")
+                        .long("uplink-interval")
+                        .about("Time between uplink summaries, e.g. 60s or 15min")
+                        .takes_value(true)
+                        .default_value("60s"),
+                ),
+        )
+        .subcommand(
+            help.app("uplink")
+                .arg(
+                    Arg::new("http")
+                        .long("http")
+                        .about("URL to POST the summaries to")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .about("Time between summaries, e.g. 60s or 15min")
+                        .takes_value(true)
+                        .default_value("60s"),
+                ),
+        )
+        .subcommand(
+            help.app("aid")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    help.app("aid ascii")
+                        .arg(
+                            Arg::new("port")
+                                .long("port")
+                                .about("Serial port the sensor is on")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("baud")
+                                .long("baud")
+                                .about("Baud rate of the sensor")
+                                .takes_value(true)
+                                .default_value("9600"),
+                        )
+                        .arg(
+                            Arg::new("parser")
+                                .long("parser")
+                                .about("Line template, e.g. '$VEL,{speed}'. Fields: speed, heading, altitude, their *_uncertainty and _ to skip")
+                                .takes_value(true)
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            help.app("mission")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    help.app("mission run")
+                        .arg(
+                            Arg::new("PROFILE")
+                                .about("YAML mission profile")
+                                .takes_value(true)
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            help.app("convert")
+                .arg(
+                    Arg::new("INPUT")
+                        .about("Capture file to read")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("OUTPUT")
+                        .about("File to write")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .about("Output format, taken from the OUTPUT extension if not given. mat writes each channel as an Nx2 [unix time, value] matrix, feather is Arrow IPC for pyarrow/pandas with one file per data set")
+                        .takes_value(true)
+                        .possible_values(convert::FORMATS),
+                ),
+        )
+        .subcommand(
+            help.app("config")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    help.app("config dump")
+                        .arg(
+                            Arg::new("OUTPUT")
+                                .about("File to write, stdout if not given")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    help.app("config audit")
+                        .arg(
+                            Arg::new("baseline")
+                                .long("baseline")
+                                .about("TOML baseline, as written by config dump")
+                                .takes_value(true)
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            help.app("stats")
+                .arg(
+                    Arg::new("INPUT")
+                        .about("Capture file to read")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            help.app("recover")
+                .arg(
+                    Arg::new("INPUT")
+                        .about("Capture or Arrow IPC file left by a run that didn't finish, usually *.partial")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("OUTPUT")
+                        .about("Where to write what can be salvaged. Defaults to INPUT without .partial")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(help.app("list"))
+        .subcommand(
+            help.app("discover")
+                .arg(
+                    Arg::new("scan-bauds")
+                        .long("scan-bauds")
+                        .about("Try every common baud rate rather than just the default"),
+                )
+                .arg(
+                    Arg::new("timeout")
+                        .long("timeout")
+                        .about("Milliseconds to wait for a reply at each baud rate")
+                        .takes_value(true)
+                        .default_value("250"),
+                ),
+        )
+        .subcommand(help.app("rate"))
+        .subcommand(help.app("packet"))
+        .subcommand(help.app("ekf"))
+        .subcommand(
+            help.app("quickstart")
+                .arg(
+                    Arg::new("duration")
+                        .long("duration")
+                        .about("Seconds to measure data rates for")
+                        .takes_value(true)
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::new("save")
+                        .long("save")
+                        .about("Save the configuration as the startup settings"),
+                ),
+        )
         .about("Get base rates")
         .get_matches();
 
-    let port_name = matches.value_of("PORT").unwrap();
-    let serial = serialport::new(port_name, 115200)
-        .open()
-        .unwrap_or_else(|e| {
+    let times = time::TimeFormatter::from_matches(&matches)?;
+    let config = config::Config::from_matches(&matches)?;
+    // Commands that don't talk to a single device
+    if let Some(matches) = matches.subcommand_matches("discover") {
+        return discover::run(matches, config.base_pacing(matches)?);
+    }
+    if let Some(matches) = matches.subcommand_matches("convert") {
+        return convert::run(matches, &config, &times);
+    }
+    if let Some(matches) = matches.subcommand_matches("stats") {
+        return stats::run(matches, &config);
+    }
+    if let Some(matches) = matches.subcommand_matches("recover") {
+        return recover::run(matches);
+    }
+
+    let found;
+    let port_name = match matches.value_of("PORT") {
+        Some(port_name) => port_name,
+        None if matches.subcommand_matches("quickstart").is_some() => {
+            found = discover::first(config.base_pacing(&matches)?)?
+                .ok_or("No responsive devices found, give the PORT it's on")?;
+            found.as_str()
+        }
+        None => return Err("A serial port is required".into()),
+    };
+    let port_lock = lock::PortLock::acquire(port_name, matches.is_present("steal"))?;
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock::new());
+    let mut serial = match transport::open(port_name, clock.clone()) {
+        Ok(serial) => serial,
+        Err(e) => {
             eprintln!("Failed to open. Error: {}", e);
+            // exit() skips destructors
+            drop(port_lock);
             ::std::process::exit(0);
-        });
+        }
+    };
+    // Before the resync, the model query reads in big chunks and would misalign it again
+    let pacing = config.pacing(&*serial, clock.clone(), &matches)?;
+    match transport::resync(&mut *serial, &*clock) {
+        Ok(0) => (),
+        Ok(discarded) => eprintln!("Discarded {} stale bytes from {}", discarded, port_name),
+        // The parser can still find its own way to a packet boundary
+        Err(e) => eprintln!("Warning: {}", e),
+    }
+    let serial = transport::pace(serial, pacing, clock.clone());
 
     let mut lord = Lord::new(serial);
     lord.start();
 
-    if let Some(_) = matches.subcommand_matches("test") {
-        loop {
+    if let Some(matches) = matches.subcommand_matches("test") {
+        if let Some(matches) = matches.subcommand_matches("soak") {
+            let mut reopen = || -> Result<Lord, Error> {
+                let mut serial = transport::open(port_name, clock.clone())?;
+                // Nothing may be streaming yet if it rebooted, the parser copes either way
+                transport::resync(&mut *serial, &*clock).ok();
+                let mut lord = Lord::new(transport::pace(serial, pacing, clock.clone()));
+                lord.start();
+                Ok(lord)
+            };
+            return soak::run(lord, &mut reopen, matches, &config, &times);
+        }
+
+        let stop = partial::stop_flag()?;
+        while !stop.load(Ordering::Relaxed) {
             if let Some(data) = lord.get_data() {
                 println!("{:02X?}", data);
             }
         }
+        io::stdout().flush()?;
+        return Ok(());
     }
 
-    if let Some(_) = matches.subcommand_matches("rate") {
-        println!("IMU Rate: {:#?}", lord.imu_base_rate()?);
-        println!("GNSS Rate: {:#?}", lord.gnss_base_rate()?);
-    }
-
-    if let Some(_) = matches.subcommand_matches("configure") {
-        lord.set_imu_format(
-            0x01,
-            vec![(0x06, 50), (0x04, 50), (0x05, 50), (0x0A, 50), (0x17, 50)],
-        )?;
-        println!("IMU Configured");
-
-        lord.set_gnss_format(
-            0x01,
-            vec![
-                (0x09, 5),
-                (0x0B, 5),
-                (0x03, 5),
-                (0x07, 5),
-                (0x04, 5)
-            ]
-        )?;
-        println!("GNSS Configured");
-
-    }
-
-    if let Some(_) = matches.subcommand_matches("packet") {
-        let packet = Packet::new(
-            0x0C,
-            vec![
-                // Write IMU Format
-                Field::new(0x08, vec![
-                    0x01, // Function
-                    0x05,
-                    0x17,
-                    0x00, 0x0A,
-                    0x06,
-                    0x00, 0x0A,
-                    0x04,
-                    0x00, 0x0A,
-                    0x05,
-                    0x00, 0x0A,
-                    0x0A,
-                    0x00, 0x0A,
-
-                ]),
-                // Write GNSS Format
-                Field::new(0x09, vec![
-                    0x01, // Function
-                    0x05,
-                    0x09,
-                    0x00, 0x01,
-                    0x0B,
-                    0x00, 0x01,
-                    0x03,
-                    0x00, 0x01,
-                    0x07,
-                    0x00, 0x01,
-                    0x05,
-                    0x00, 0x01,
-                ]),
-                Field::new(0x0A, vec![
-                    0x01,
-                    0x05,
-                    0x11, 
-                    0x00, 0x0A,
-                    0x01, 
-                    0x00, 0x0A,
-                    0x02, 
-                    0x00, 0x0A, 
-                    0x03, 
-                    0x00, 0x0A,
-                    0x10, 
-                    0x00, 0x0A
-                ]),
-
-                // Save IMU and GNSS Format
-                Field::new(0x08, vec![
-                    0x03
-                ]),
-                Field::new(0x09, vec![
-                    0x03
-                ]),
-                Field::new(0x0A, vec![
-                    0x03
-                ]),
-                // Enable IMU/GNSS Streams/EKF
-                Field::new(0x11, vec![
-                    0x01,
-                    0x01,
-                    0x01
-                ]),
-                Field::new(0x11, vec![
-                    0x01,
-                    0x02,
-                    0x01
-                ]),
-                Field::new(0x11, vec![
-                    0x01,
-                    0x03,
-                    0x01
-                ]),
-                // Save stream settings for startup
-                Field::new(0x11, vec![0x03, 0x01]),
-                Field::new(0x11, vec![0x03, 0x02]),
-                Field::new(0x11, vec![0x03, 0x03]),
-
-                Field::new(0x0D, vec![]),
-                Field::new(0x19, vec![0x02]),
-                Field::new(0x19, vec![0x03, 0x01])
-
-
-            ]
-        );
+    if let Some(matches) = matches.subcommand_matches("quickstart") {
+        quickstart::run(&mut lord, matches)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("monitor") {
+        monitor::run(&mut lord, matches, &config, &times)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("aid") {
+        aid::run(&mut lord, matches)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("mission") {
+        mission::run(&mut lord, matches, &config)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("config") {
+        settings::run(&mut lord, matches)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("record") {
+        record::run(&mut lord, matches, &config, &times)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("uplink") {
+        uplink::run(&mut lord, matches, &config)?;
+    }
+
+    if matches.subcommand_matches("rate").is_some() {
+        for set in profile::RATE {
+            println!("{} Rate: {}", set.name, device::base_rate(&mut lord, *set)?);
+        }
+    }
+
+    if matches.subcommand_matches("configure").is_some() {
+        for (set, fields) in profile::CONFIGURE {
+            device::set_format(&mut lord, *set, fields)?;
+            println!("{} Configured", set.name);
+        }
+    }
+
+    if matches.subcommand_matches("packet").is_some() {
+        let set = profile::PACKET[0].0.set;
+        let fields = profile::PACKET
+            .iter()
+            .map(|(command, data)| Field::new(command.field, data.to_vec()))
+            .collect();
+        let packet = Packet::new(set, fields);
 
         println!("{:#02X?}", packet.to_bytes()?);
         match lord.send(packet) {
@@ -170,25 +561,17 @@ fn main() -> Result<(), Error> {
         };        
     }
 
-    if let Some(_) = matches.subcommand_matches("ekf") {
-        lord.set_estimation_format(0x01, vec![
-            (0x01, 50),
-            (0x11, 50)
-        ])?;
-
-        lord.set_gnss_format(0x01, vec![
-            (0x03, 4),
-            (0x09, 4)
-        ])?;
-        
-        lord.send(Packet::new(0x0D, vec![
-            Field::new(0x19, vec![0x01, 0x01]),
-            Field::new(0x19, vec![0x03, 0x01])
-        ]))?;
+    if matches.subcommand_matches("ekf").is_some() {
+        for (set, fields) in profile::EKF {
+            device::set_format(&mut lord, *set, fields)?;
+        }
 
+        for function in &[mip::APPLY, mip::SAVE] {
+            device::send(&mut lord, mip::FILTER_AUTO_INIT, vec![*function, 0x01])?;
+        }
     }
 
-    if let Some(_) = matches.subcommand_matches("read") {
+    if matches.subcommand_matches("read").is_some() {
         let mut seconds_since: HashMap<u8, Instant> = HashMap::new();
 
         loop {
@@ -201,7 +584,7 @@ fn main() -> Result<(), Error> {
 
                 seconds_since.insert(data.header.descriptor, now);
 
-                println!("{:02}ms {}", ms, data);
+                println!("{} {:02}ms {}", times.format(capture::timestamp()), ms, data);
 
                 // if data.header.descriptor == 0x80 {
                 //     let field = data.payload.get_field(0x12).unwrap();
@@ -0,0 +1,179 @@
+//! Just enough of the MATLAB level 5 MAT-file format to write named double matrices, which
+//! every version of MATLAB since 5 (and scipy.io.loadmat) can read.
+//!
+//! The file is a 128 byte header followed by one `miMATRIX` element per variable. Every
+//! element is an 8 byte `[type: u32][length: u32]` tag and its data padded to 8 bytes.
+
+use std::{
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use chrono::Utc;
+
+use crate::{partial::PartialFile, Error};
+
+const HEADER_TEXT_LEN: usize = 116;
+const VERSION: u16 = 0x0100;
+
+// Data types
+const MI_INT8: u32 = 1;
+const MI_INT32: u32 = 5;
+const MI_UINT32: u32 = 6;
+const MI_DOUBLE: u32 = 9;
+const MI_MATRIX: u32 = 14;
+
+const MX_DOUBLE_CLASS: u32 = 6;
+const MAX_NAME_LEN: usize = 63;
+
+pub struct MatWriter {
+    out: BufWriter<PartialFile>,
+}
+
+impl MatWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut out = BufWriter::new(PartialFile::create(path)?);
+
+        let mut text = format!(
+            "MATLAB 5.0 MAT-file, Platform: lordcli, Created on: {}",
+            Utc::now().format("%a %b %e %H:%M:%S %Y")
+        )
+        .into_bytes();
+        text.resize(HEADER_TEXT_LEN, b' ');
+
+        out.write_all(&text)?;
+        // No subsystem data
+        out.write_all(&[0u8; 8])?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(b"IM")?;
+
+        Ok(MatWriter { out })
+    }
+
+    /// Write a `rows` x `cols` matrix, `data` is in column major order like MATLAB stores it.
+    pub fn write_matrix(
+        &mut self,
+        name: &str,
+        rows: usize,
+        cols: usize,
+        data: &[f64],
+    ) -> Result<(), Error> {
+        if rows * cols != data.len() {
+            return Err(format!("{} doesn't have {}x{} values", name, rows, cols).into());
+        }
+
+        let mut flags = Vec::with_capacity(8);
+        flags.extend_from_slice(&MX_DOUBLE_CLASS.to_le_bytes());
+        flags.extend_from_slice(&0u32.to_le_bytes());
+
+        let mut dimensions = Vec::with_capacity(8);
+        dimensions.extend_from_slice(&(rows as i32).to_le_bytes());
+        dimensions.extend_from_slice(&(cols as i32).to_le_bytes());
+
+        let mut real = Vec::with_capacity(data.len() * 8);
+        for value in data {
+            real.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let elements = [
+            (MI_UINT32, flags),
+            (MI_INT32, dimensions),
+            (MI_INT8, name.as_bytes().to_vec()),
+            (MI_DOUBLE, real),
+        ];
+        let len: usize = elements
+            .iter()
+            .map(|(_, data)| 8 + padded(data.len()))
+            .sum();
+
+        self.write_tag(MI_MATRIX, len)?;
+        for (kind, data) in &elements {
+            self.write_tag(*kind, data.len())?;
+            self.out.write_all(data)?;
+            self.out
+                .write_all(&[0u8; 8][..padded(data.len()) - data.len()])?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        self.out.into_inner()?.commit()?;
+        Ok(())
+    }
+
+    fn write_tag(&mut self, kind: u32, len: usize) -> Result<(), Error> {
+        self.out.write_all(&kind.to_le_bytes())?;
+        self.out.write_all(&(len as u32).to_le_bytes())?;
+        Ok(())
+    }
+}
+
+fn padded(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+/// Turn a channel name like `filter.roll` into a valid MATLAB variable name.
+pub fn variable_name(channel: &str) -> String {
+    let mut name: String = channel
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        name.insert(0, 'x');
+    }
+    name.truncate(MAX_NAME_LEN);
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn one_small_matrix() {
+        let path = env::temp_dir().join(format!("lordcli-mat-{}.mat", process::id()));
+        let mut writer = MatWriter::create(&path).unwrap();
+        writer
+            .write_matrix("a", 2, 2, &[1.0, 2.0, 3.0, 4.0])
+            .unwrap();
+        writer.finish().unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert!(bytes.starts_with(b"MATLAB 5.0 MAT-file, Platform: lordcli"));
+        assert_eq!(
+            &bytes[116..128],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x01, b'I', b'M']
+        );
+
+        let mut expected = vec![];
+        #[rustfmt::skip]
+        expected.extend_from_slice(&[
+            14, 0, 0, 0, 88, 0, 0, 0,
+            // Array flags, double class
+            6, 0, 0, 0, 8, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0,
+            // Dimensions
+            5, 0, 0, 0, 8, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0,
+            // Name, padded to 8 bytes
+            1, 0, 0, 0, 1, 0, 0, 0, b'a', 0, 0, 0, 0, 0, 0, 0,
+            // Real part
+            9, 0, 0, 0, 32, 0, 0, 0,
+        ]);
+        for value in &[1.0f64, 2.0, 3.0, 4.0] {
+            expected.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(&bytes[128..], &expected[..]);
+    }
+
+    #[test]
+    fn variable_names() {
+        assert_eq!(variable_name("filter.roll"), "filter_roll");
+        assert_eq!(variable_name("unknown.0x80.0x99"), "unknown_0x80_0x99");
+        assert_eq!(variable_name("2d"), "x2d");
+        assert_eq!(variable_name(&"a".repeat(100)).len(), MAX_NAME_LEN);
+    }
+}
@@ -0,0 +1,469 @@
+use desert::ToBytes;
+use lordserial::{Field, Packet};
+
+use crate::Error;
+
+pub const SYNC_ONE: u8 = 0x75;
+pub const SYNC_TWO: u8 = 0x65;
+
+// Header is sync bytes, descriptor set and payload length, followed by a two byte checksum
+const HEADER_LEN: usize = 4;
+const CHECKSUM_LEN: usize = 2;
+// Both lengths are a single byte, and a field's counts its own two byte header
+const MAX_PAYLOAD_LEN: usize = u8::MAX as usize;
+const MAX_FIELD_DATA_LEN: usize = u8::MAX as usize - 2;
+
+pub const ACK_FIELD: u8 = 0xF1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Command {
+    pub set: u8,
+    pub field: u8,
+}
+
+pub const PING: Command = Command {
+    set: 0x01,
+    field: 0x01,
+};
+pub const SET_IDLE: Command = Command {
+    set: 0x01,
+    field: 0x02,
+};
+pub const RESUME: Command = Command {
+    set: 0x01,
+    field: 0x06,
+};
+pub const DEVICE_INFO: Command = Command {
+    set: 0x01,
+    field: 0x03,
+};
+pub const IMU_BASE_RATE: Command = Command {
+    set: 0x0C,
+    field: 0x06,
+};
+pub const GNSS_BASE_RATE: Command = Command {
+    set: 0x0C,
+    field: 0x07,
+};
+pub const FILTER_BASE_RATE: Command = Command {
+    set: 0x0C,
+    field: 0x0B,
+};
+pub const IMU_FORMAT: Command = Command {
+    set: 0x0C,
+    field: 0x08,
+};
+pub const GNSS_FORMAT: Command = Command {
+    set: 0x0C,
+    field: 0x09,
+};
+pub const FILTER_FORMAT: Command = Command {
+    set: 0x0C,
+    field: 0x0A,
+};
+pub const ENABLE_STREAM: Command = Command {
+    set: 0x0C,
+    field: 0x11,
+};
+pub const EXTERNAL_HEADING: Command = Command {
+    set: 0x0D,
+    field: 0x17,
+};
+pub const SPEED_MEASUREMENT: Command = Command {
+    set: 0x0D,
+    field: 0x60,
+};
+pub const HEIGHT_ABOVE_ELLIPSOID: Command = Command {
+    set: 0x13,
+    field: 0x23,
+};
+pub const FILTER_AUTO_INIT: Command = Command {
+    set: 0x0D,
+    field: 0x19,
+};
+
+/// Names for the commands above, for help text.
+pub const COMMAND_NAMES: &[(Command, &str)] = &[
+    (PING, "Ping"),
+    (SET_IDLE, "Set to idle"),
+    (RESUME, "Resume"),
+    (DEVICE_INFO, "Get device information"),
+    (IMU_BASE_RATE, "Get IMU base rate"),
+    (GNSS_BASE_RATE, "Get GNSS base rate"),
+    (FILTER_BASE_RATE, "Get filter base rate"),
+    (IMU_FORMAT, "IMU message format"),
+    (GNSS_FORMAT, "GNSS message format"),
+    (FILTER_FORMAT, "Filter message format"),
+    (ENABLE_STREAM, "Enable data stream"),
+    (EXTERNAL_HEADING, "External heading update"),
+    (SPEED_MEASUREMENT, "Speed measurement"),
+    (HEIGHT_ABOVE_ELLIPSOID, "Height above ellipsoid"),
+    (FILTER_AUTO_INIT, "Filter auto-initialization control"),
+];
+
+pub fn command_name(command: Command) -> &'static str {
+    COMMAND_NAMES
+        .iter()
+        .find(|(c, _)| *c == command)
+        .map_or("Unknown command", |(_, name)| name)
+}
+
+// Data descriptor sets
+pub const IMU_DATA: u8 = 0x80;
+pub const GNSS_DATA: u8 = 0x81;
+pub const FILTER_DATA: u8 = 0x82;
+
+// Function selectors shared by most settings commands
+pub const APPLY: u8 = 0x01;
+pub const READ: u8 = 0x02;
+pub const SAVE: u8 = 0x03;
+pub const LOAD: u8 = 0x04;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawField {
+    pub descriptor: u8,
+    pub data: Vec<u8>,
+}
+
+impl RawField {
+    pub fn new(descriptor: u8, data: Vec<u8>) -> Self {
+        RawField { descriptor, data }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub descriptor: u8,
+    pub fields: Vec<RawField>,
+}
+
+impl Frame {
+    pub fn new(descriptor: u8, fields: Vec<RawField>) -> Self {
+        Frame { descriptor, fields }
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<Self, Error> {
+        Frame::parse(&packet.to_bytes()?).ok_or_else(|| "Malformed packet".into())
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        Packet::new(
+            self.descriptor,
+            self.fields
+                .iter()
+                .map(|f| Field::new(f.descriptor, f.data.clone()))
+                .collect(),
+        )
+    }
+
+    /// Parse a single complete frame, rejecting it if the checksum or field lengths don't line up.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN + CHECKSUM_LEN || bytes[0] != SYNC_ONE || bytes[1] != SYNC_TWO {
+            return None;
+        }
+
+        let end = HEADER_LEN + bytes[3] as usize;
+        if bytes.len() != end + CHECKSUM_LEN || checksum(&bytes[..end]) != bytes[end..] {
+            return None;
+        }
+
+        let mut fields = Vec::new();
+        let mut offset = HEADER_LEN;
+        while offset < end {
+            let length = bytes[offset] as usize;
+            if length < 2 || offset + length > end {
+                return None;
+            }
+
+            fields.push(RawField::new(
+                bytes[offset + 1],
+                bytes[offset + 2..offset + length].to_vec(),
+            ));
+            offset += length;
+        }
+
+        Some(Frame::new(bytes[2], fields))
+    }
+
+    pub fn field(&self, descriptor: u8) -> Option<&RawField> {
+        self.fields.iter().find(|f| f.descriptor == descriptor)
+    }
+
+    /// Error code of the ack for `command`, if this frame acknowledges it.
+    pub fn ack(&self, command: Command) -> Option<u8> {
+        if self.descriptor != command.set {
+            return None;
+        }
+
+        self.fields
+            .iter()
+            .filter(|f| f.descriptor == ACK_FIELD && f.data.len() >= 2)
+            .find(|f| f.data[0] == command.field)
+            .map(|f| f.data[1])
+    }
+
+    /// The frame as it goes over the wire, failing if a field or the payload is too long to
+    /// have its length fit in a byte.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![SYNC_ONE, SYNC_TWO, self.descriptor, 0];
+        for field in &self.fields {
+            if field.data.len() > MAX_FIELD_DATA_LEN {
+                return Err(format!(
+                    "Field 0x{:02X}/0x{:02X} has {} bytes, at most {} fit in a packet",
+                    self.descriptor,
+                    field.descriptor,
+                    field.data.len(),
+                    MAX_FIELD_DATA_LEN
+                )
+                .into());
+            }
+
+            bytes.push(field.data.len() as u8 + 2);
+            bytes.push(field.descriptor);
+            bytes.extend_from_slice(&field.data);
+        }
+
+        let payload = bytes.len() - HEADER_LEN;
+        if payload > MAX_PAYLOAD_LEN {
+            return Err(format!(
+                "Packet 0x{:02X} has a {} byte payload, at most {} fit",
+                self.descriptor, payload, MAX_PAYLOAD_LEN
+            )
+            .into());
+        }
+
+        bytes[3] = payload as u8;
+        let checksum = checksum(&bytes);
+        bytes.extend_from_slice(&checksum);
+        Ok(bytes)
+    }
+}
+
+pub fn command(command: Command, data: Vec<u8>) -> Frame {
+    Frame::new(command.set, vec![RawField::new(command.field, data)])
+}
+
+// Fletcher checksum over everything from the first sync byte to the end of the payload
+pub fn checksum(bytes: &[u8]) -> [u8; 2] {
+    let (mut msb, mut lsb) = (0u8, 0u8);
+    for byte in bytes {
+        msb = msb.wrapping_add(*byte);
+        lsb = lsb.wrapping_add(msb);
+    }
+
+    [msb, lsb]
+}
+
+/// Splits a raw byte stream into frames, skipping over anything that doesn't checksum.
+#[derive(Debug, Default)]
+pub struct Scanner {
+    buffer: Vec<u8>,
+    discarded: usize,
+}
+
+impl Scanner {
+    pub fn new() -> Self {
+        Scanner::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Number of bytes thrown away while looking for a valid frame.
+    pub fn discarded(&self) -> usize {
+        self.discarded
+    }
+
+    /// Bytes received that are not yet part of a complete frame.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn next_frame(&mut self) -> Option<(Frame, Vec<u8>)> {
+        loop {
+            match self
+                .buffer
+                .windows(2)
+                .position(|w| w == [SYNC_ONE, SYNC_TWO])
+            {
+                Some(start) => self.discard(start),
+                None => {
+                    // Keep a trailing sync byte around in case the rest of the header is still in flight
+                    let keep = (self.buffer.last() == Some(&SYNC_ONE)) as usize;
+                    self.discard(self.buffer.len() - keep);
+                    return None;
+                }
+            }
+
+            if self.buffer.len() < HEADER_LEN {
+                return None;
+            }
+
+            let total = HEADER_LEN + self.buffer[3] as usize + CHECKSUM_LEN;
+            if self.buffer.len() < total {
+                return None;
+            }
+
+            match Frame::parse(&self.buffer[..total]) {
+                Some(frame) => {
+                    let bytes = self.buffer.drain(..total).collect();
+                    return Some((frame, bytes));
+                }
+                // False sync, step past it and keep looking
+                None => self.discard(1),
+            }
+        }
+    }
+
+    fn discard(&mut self, count: usize) {
+        self.buffer.drain(..count);
+        self.discarded += count;
+    }
+}
+
+/// Follows bytes on their way to the device and finds where each packet starts, keeping
+/// nothing but its place, so packets can be split across any number of writes.
+#[derive(Debug, Default)]
+pub struct PacketStarts {
+    // Header bytes of the next packet seen so far
+    header: usize,
+    // Bytes of the current packet still to come after its header
+    remaining: usize,
+}
+
+impl PacketStarts {
+    /// Offsets into `bytes` where a packet starts.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<usize> {
+        let mut starts = Vec::new();
+
+        for (i, byte) in bytes.iter().enumerate() {
+            if self.remaining > 0 {
+                self.remaining -= 1;
+                continue;
+            }
+
+            self.header = match (self.header, *byte) {
+                (0, SYNC_ONE) | (1, SYNC_ONE) => {
+                    starts.push(i);
+                    1
+                }
+                (1, SYNC_TWO) => 2,
+                (0, _) | (1, _) => 0,
+                (2, _) => 3,
+                (_, len) => {
+                    self.remaining = len as usize + CHECKSUM_LEN;
+                    0
+                }
+            };
+        }
+
+        starts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> Frame {
+        Frame::new(
+            IMU_DATA,
+            vec![
+                RawField::new(0x04, vec![0x3F, 0x80, 0x00, 0x00]),
+                RawField::new(0x05, vec![]),
+            ],
+        )
+    }
+
+    #[test]
+    fn encode_parse_round_trip() {
+        let bytes = frame().encode().unwrap();
+        assert_eq!(&bytes[..4], &[SYNC_ONE, SYNC_TWO, IMU_DATA, 8]);
+        assert_eq!(Frame::parse(&bytes), Some(frame()));
+    }
+
+    #[test]
+    fn encode_limits() {
+        let longest = Frame::new(0x0C, vec![RawField::new(0x01, vec![0; MAX_FIELD_DATA_LEN])]);
+        let bytes = longest.encode().unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + MAX_PAYLOAD_LEN + CHECKSUM_LEN);
+        assert_eq!(Frame::parse(&bytes), Some(longest));
+
+        let field = Frame::new(
+            0x0C,
+            vec![RawField::new(0x01, vec![0; MAX_FIELD_DATA_LEN + 1])],
+        );
+        assert!(field.encode().is_err());
+
+        let payload = Frame::new(
+            0x0C,
+            vec![
+                RawField::new(0x01, vec![0; 200]),
+                RawField::new(0x02, vec![0; 60]),
+            ],
+        );
+        assert!(payload.encode().is_err());
+    }
+
+    #[test]
+    fn parse_rejects_bad_checksum() {
+        let mut bytes = frame().encode().unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        assert_eq!(Frame::parse(&bytes), None);
+    }
+
+    #[test]
+    fn ack_matches_command() {
+        let reply = command(PING, vec![]);
+        assert_eq!(reply.ack(PING), None);
+
+        let reply = Frame::new(0x01, vec![RawField::new(ACK_FIELD, vec![0x01, 0x03])]);
+        assert_eq!(reply.ack(PING), Some(0x03));
+        assert_eq!(reply.ack(SET_IDLE), None);
+        assert_eq!(reply.ack(IMU_FORMAT), None);
+    }
+
+    #[test]
+    fn scanner_resyncs_and_reassembles() {
+        let bytes = frame().encode().unwrap();
+        let mut corrupt = bytes.clone();
+        corrupt[5] ^= 0xFF;
+
+        let mut scanner = Scanner::new();
+        scanner.push(&[0x00, SYNC_ONE, 0x12]);
+        scanner.push(&corrupt);
+        scanner.push(&bytes[..3]);
+        assert!(scanner.next_frame().is_none());
+
+        scanner.push(&bytes[3..]);
+        let (parsed, raw) = scanner.next_frame().unwrap();
+        assert_eq!(parsed, frame());
+        assert_eq!(raw, bytes);
+        assert_eq!(scanner.discarded(), 3 + corrupt.len());
+        assert_eq!(scanner.pending(), 0);
+        assert!(scanner.next_frame().is_none());
+    }
+
+    #[test]
+    fn packet_starts_skip_sync_bytes_in_payloads() {
+        let first = Frame::new(
+            0x0C,
+            vec![RawField::new(0x08, vec![SYNC_ONE, SYNC_TWO, 0x01])],
+        )
+        .encode()
+        .unwrap();
+        let second = command(PING, vec![]).encode().unwrap();
+        let mut bytes = first.clone();
+        bytes.extend(&second);
+
+        let mut starts = PacketStarts::default();
+        assert_eq!(starts.push(&bytes), vec![0, first.len()]);
+
+        // Split inside the header and again inside the payload
+        let mut starts = PacketStarts::default();
+        assert_eq!(starts.push(&bytes[..3]), vec![0]);
+        assert!(starts.push(&bytes[3..8]).is_empty());
+        assert_eq!(starts.push(&bytes[8..]), vec![first.len() - 8]);
+    }
+}
@@ -0,0 +1,609 @@
+//! Multi stage field protocols described in a YAML file, e.g.
+//!
+//! ```yaml
+//! name: Survey
+//! phases:
+//!   - name: warmup
+//!     duration: 5min
+//!     streams:
+//!       imu: { rate: 10 }
+//!     output: warmup.cap
+//!   - name: survey
+//!     enter: gnss.fix_type == 3
+//!     enter_timeout: 10min
+//!     duration: 1h
+//!     streams:
+//!       imu: { rate: 100 }
+//!       gnss: { rate: 4 }
+//!       filter: { rate: 50, fields: [0x01, 0x05, 0x10] }
+//!     output: survey.cap
+//! ```
+//!
+//! Each phase reconfigures the device, waits for its entry condition (if any) and then
+//! records for its duration. Data sets a phase doesn't list are turned off. Only the last
+//! phase may leave out the duration, in which case it runs until interrupted.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use clap::ArgMatches;
+use desert::ToBytes;
+use lordserial::parser::Lord;
+use serde::Deserialize;
+
+use crate::{
+    capture::{self, CaptureWriter, Kind},
+    channels::{self, Decoder},
+    clock::{Clock, SystemClock},
+    config::Config,
+    device::{self, DataSet},
+    expr::Expr,
+    mip::Frame,
+    partial, profile, time, Error,
+};
+
+// Bounds how much data is lost if a phase that runs indefinitely is interrupted
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// How often to say what an entry condition is still waiting on
+const WAIT_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+// How long to wait for more data while there's none to check an entry condition against
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MissionFile {
+    name: Option<String>,
+    phases: Vec<PhaseFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PhaseFile {
+    name: String,
+    duration: Option<String>,
+    enter: Option<String>,
+    enter_timeout: Option<String>,
+    #[serde(default)]
+    streams: BTreeMap<String, StreamFile>,
+    output: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StreamFile {
+    rate: u16,
+    fields: Option<Vec<u8>>,
+}
+
+/// A mission with every expression, duration and stream name checked up front, so a typo
+/// in the last phase doesn't show up an hour into the first.
+pub struct Mission {
+    pub name: String,
+    pub phases: Vec<Phase>,
+}
+
+pub struct Phase {
+    pub name: String,
+    pub duration: Option<Duration>,
+    pub enter: Option<(String, Expr)>,
+    pub enter_timeout: Option<Duration>,
+    pub streams: Vec<(DataSet, Stream)>,
+    pub output: Option<String>,
+}
+
+pub struct Stream {
+    pub rate: u16,
+    pub fields: Vec<u8>,
+}
+
+impl Mission {
+    /// Channels in entry conditions are looked up through `decoder`, so derived ones work too.
+    pub fn load(path: &str, decoder: &Decoder) -> Result<Self, Error> {
+        Mission::parse(&fs::read_to_string(path)?, path, decoder)
+    }
+
+    /// `path` names the mission in errors and if the file doesn't give it a name.
+    fn parse(text: &str, path: &str, decoder: &Decoder) -> Result<Self, Error> {
+        let file: MissionFile =
+            serde_yaml::from_str(text).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+        if file.phases.is_empty() {
+            return Err(format!("{} has no phases", path).into());
+        }
+
+        let count = file.phases.len();
+        let mut phases = Vec::new();
+        for (i, phase) in file.phases.into_iter().enumerate() {
+            let phase =
+                Phase::check(phase, decoder).map_err(|e| format!("Phase {}: {}", i + 1, e))?;
+            if phase.duration.is_none() && i + 1 != count {
+                return Err(format!(
+                    "Phase {} needs a duration, only the last phase can run indefinitely",
+                    phase.name
+                )
+                .into());
+            }
+            phases.push(phase);
+        }
+
+        Ok(Mission {
+            name: file.name.unwrap_or_else(|| path.to_string()),
+            phases,
+        })
+    }
+}
+
+impl Phase {
+    fn check(file: PhaseFile, decoder: &Decoder) -> Result<Self, Error> {
+        let mut streams = Vec::new();
+        for (name, stream) in file.streams {
+            let set = device::DATA_SETS
+                .iter()
+                .find(|s| s.name.eq_ignore_ascii_case(&name))
+                .ok_or_else(|| {
+                    format!("Unknown data set {}, expected imu, gnss or filter", name)
+                })?;
+
+            let fields = match stream.fields {
+                Some(fields) => fields,
+                None => profile::DEFAULT_FORMATS
+                    .iter()
+                    .find(|f| f.set == set.descriptor)
+                    .map(|f| f.fields.to_vec())
+                    .unwrap_or_default(),
+            };
+            if stream.rate == 0 || fields.is_empty() {
+                return Err(format!("{} needs a non-zero rate and some fields", name).into());
+            }
+
+            streams.push((
+                *set,
+                Stream {
+                    rate: stream.rate,
+                    fields,
+                },
+            ));
+        }
+
+        let enter = match file.enter {
+            Some(source) => {
+                let expr = Expr::parse(&source)?;
+                for name in expr.channels() {
+                    // A condition on a channel that isn't streamed would wait forever, and a
+                    // derived channel needs every channel it's computed from
+                    let sources = decoder
+                        .sources(name)
+                        .ok_or_else(|| format!("Unknown channel {}", name))?;
+                    let streamed = sources
+                        .iter()
+                        .filter_map(|source| channels::spec_of(source))
+                        .all(|spec| {
+                            streams.iter().any(|(set, stream)| {
+                                set.descriptor == spec.set && stream.fields.contains(&spec.field)
+                            })
+                        });
+                    if !streamed {
+                        return Err(format!(
+                            "{} isn't streamed in phase {}, so it can't be used to enter it",
+                            name, file.name
+                        )
+                        .into());
+                    }
+                }
+                Some((source, expr))
+            }
+            None => None,
+        };
+
+        Ok(Phase {
+            duration: file
+                .duration
+                .as_deref()
+                .map(time::parse_duration)
+                .transpose()?,
+            enter_timeout: file
+                .enter_timeout
+                .as_deref()
+                .map(time::parse_duration)
+                .transpose()?,
+            name: file.name,
+            enter,
+            streams,
+            output: file.output,
+        })
+    }
+}
+
+pub fn run(lord: &mut Lord, matches: &ArgMatches, config: &Config) -> Result<(), Error> {
+    let matches = match matches.subcommand_matches("run") {
+        Some(matches) => matches,
+        None => return Err("Expected a mission command, e.g. mission run profile.yaml".into()),
+    };
+
+    let mut decoder = Decoder::from_matches(matches, config)?;
+    let mission = Mission::load(matches.value_of("PROFILE").unwrap(), &decoder)?;
+    let clock = SystemClock::new();
+    let mut base_rates = HashMap::new();
+    let stop = partial::stop_flag()?;
+
+    println!(
+        "Running mission {} ({} phases)",
+        mission.name,
+        mission.phases.len()
+    );
+
+    for (i, phase) in mission.phases.iter().enumerate() {
+        println!("Phase {}/{}: {}", i + 1, mission.phases.len(), phase.name);
+        configure(lord, phase, &mut base_rates)?;
+
+        if let Some((source, expr)) = &phase.enter {
+            println!("Waiting for {}", source);
+            let mut next = || lord.get_data().map(|d| Frame::from_packet(&d)).transpose();
+            wait_for(
+                &mut next,
+                &mut decoder,
+                &clock,
+                expr,
+                phase.enter_timeout,
+                &stop,
+            )
+            .map_err(|e| format!("Phase {} never started: {} {}", phase.name, source, e))?;
+        }
+
+        let packets = record(lord, &clock, phase, &stop)?;
+        if stop.load(Ordering::Relaxed) {
+            println!(
+                "Mission {} stopped in phase {}, {} packets",
+                mission.name, phase.name, packets
+            );
+            return Ok(());
+        }
+        println!("Phase {} finished, {} packets", phase.name, packets);
+    }
+
+    println!("Mission {} complete", mission.name);
+    Ok(())
+}
+
+/// Apply a phase's formats, turning off every data set it doesn't use.
+fn configure(
+    lord: &mut Lord,
+    phase: &Phase,
+    base_rates: &mut HashMap<u8, u16>,
+) -> Result<(), Error> {
+    for set in device::DATA_SETS.iter() {
+        let stream = match phase.streams.iter().find(|(s, _)| s == set) {
+            Some((_, stream)) => stream,
+            None => {
+                // Not every model has every data set, so a failure here is expected
+                device::enable_stream(lord, *set, false).ok();
+                continue;
+            }
+        };
+
+        let base_rate = match base_rates.get(&set.descriptor) {
+            Some(rate) => *rate,
+            None => {
+                let rate = device::base_rate(lord, *set)?;
+                base_rates.insert(set.descriptor, rate);
+                rate
+            }
+        };
+
+        let decimation = (base_rate / stream.rate).max(1);
+        let fields: Vec<(u8, u16)> = stream.fields.iter().map(|f| (*f, decimation)).collect();
+        device::set_format(lord, *set, &fields)?;
+        device::enable_stream(lord, *set, true)?;
+    }
+
+    Ok(())
+}
+
+/// Wait for `expr` to hold on the packets from `next`, which gives `None` when nothing is
+/// waiting.
+fn wait_for(
+    next: &mut dyn FnMut() -> Result<Option<Frame>, Error>,
+    decoder: &mut Decoder,
+    clock: &dyn Clock,
+    expr: &Expr,
+    timeout: Option<Duration>,
+    stop: &AtomicBool,
+) -> Result<(), Error> {
+    // Everything queued before the phase's last command was acked came from the previous
+    // phase's formats and could satisfy the condition on stale values
+    while next()?.is_some() {}
+
+    let mut latest: HashMap<String, f64> = HashMap::new();
+    let start = clock.now();
+    let mut last_report = start;
+
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return Err("was interrupted".into());
+        }
+
+        match next()? {
+            Some(frame) => {
+                let decoded = decoder.decode(&frame)?;
+                for unknown in decoded.unknown.iter().filter(|u| decoder.is_first(u)) {
+                    eprintln!("{}", unknown);
+                }
+                latest.extend(decoded.values);
+                if let Some(value) = expr.eval(&|name| latest.get(name).copied()) {
+                    if value != 0.0 {
+                        return Ok(());
+                    }
+                }
+            }
+            None => clock.sleep(POLL_INTERVAL),
+        }
+
+        let now = clock.now();
+        if let Some(timeout) = timeout {
+            if now - start > timeout {
+                return Err("wasn't met in time".into());
+            }
+        }
+        if now - last_report >= WAIT_REPORT_INTERVAL {
+            last_report = now;
+            let values: Vec<String> = expr
+                .channels()
+                .iter()
+                .map(|name| match latest.get(*name) {
+                    Some(value) => format!("{}={:.4}", name, value),
+                    None => format!("{}=-", name),
+                })
+                .collect();
+            println!("Still waiting, {}", values.join(" "));
+        }
+    }
+}
+
+/// Ends early if `stop` is set, with the output still finished.
+fn record(
+    lord: &mut Lord,
+    clock: &dyn Clock,
+    phase: &Phase,
+    stop: &AtomicBool,
+) -> Result<u64, Error> {
+    let mut output = match &phase.output {
+        Some(path) => {
+            println!("Recording to {}", path);
+            let mut writer = CaptureWriter::create(path)?;
+            let event = format!("mission phase={}", phase.name);
+            writer.write(Kind::Event, capture::timestamp(), event.as_bytes())?;
+            Some(writer)
+        }
+        None => None,
+    };
+
+    let start = clock.now();
+    let mut last_flush = start;
+    let mut packets = 0;
+    loop {
+        let now = clock.now();
+        if let Some(duration) = phase.duration {
+            if now - start >= duration {
+                break;
+            }
+        }
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+
+        if let Some(data) = lord.get_data() {
+            if let Some(writer) = &mut output {
+                writer.write(Kind::Packet, capture::timestamp(), &data.to_bytes()?)?;
+            }
+            packets += 1;
+        }
+
+        if let Some(writer) = &mut output {
+            if now - last_flush >= FLUSH_INTERVAL {
+                writer.flush()?;
+                last_flush = now;
+            }
+        }
+    }
+
+    if let Some(writer) = output {
+        writer.finish()?;
+    }
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derived::Derived;
+
+    fn load(text: &str) -> Result<Mission, Error> {
+        Mission::parse(text, "test.yaml", &Decoder::new(false, Derived::default()))
+    }
+
+    #[test]
+    fn only_the_last_phase_can_leave_out_the_duration() {
+        let error = load(
+            "phases:
+  - name: first
+    streams: { imu: { rate: 10 } }
+  - name: second
+    duration: 1min
+    streams: { imu: { rate: 10 } }",
+        )
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("Phase first needs a duration"));
+
+        let mission = load(
+            "phases:
+  - name: first
+    duration: 1min
+    streams: { imu: { rate: 10 } }
+  - name: second
+    streams: { imu: { rate: 10 } }",
+        )
+        .unwrap();
+        assert_eq!(mission.name, "test.yaml");
+        assert_eq!(mission.phases[0].duration, Some(Duration::from_secs(60)));
+        assert_eq!(mission.phases[1].duration, None);
+    }
+
+    #[test]
+    fn unknown_data_sets_are_refused() {
+        let error = load(
+            "phases:
+  - name: first
+    streams: { accel: { rate: 10 } }",
+        )
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("Unknown data set accel"));
+    }
+
+    #[test]
+    fn entry_channels_have_to_be_streamed() {
+        let phase = |enter: &str| {
+            load(&format!(
+                "phases:
+  - name: survey
+    enter: {}
+    streams: {{ imu: {{ rate: 10 }} }}",
+                enter
+            ))
+        };
+
+        let mission = phase("imu.accel_z > 0").unwrap();
+        assert_eq!(
+            mission.phases[0].enter.as_ref().unwrap().0,
+            "imu.accel_z > 0"
+        );
+
+        let error = phase("gnss.fix_type == 3").err().unwrap();
+        assert!(error.to_string().contains("gnss.fix_type isn't streamed"));
+        let error = phase("imu.nope > 0").err().unwrap();
+        assert!(error.to_string().contains("Unknown channel imu.nope"));
+    }
+
+    #[test]
+    fn derived_entry_channels_need_their_sources_streamed() {
+        let mut definitions = BTreeMap::new();
+        definitions.insert(
+            "tilt".to_string(),
+            "atan2(imu.accel_y, imu.accel_z)".to_string(),
+        );
+        let decoder = Decoder::new(false, Derived::new(&definitions).unwrap());
+        let phase = |fields: &str| {
+            let text = format!(
+                "phases:
+  - name: level
+    enter: abs(tilt) < 2deg
+    streams: {{ imu: {{ rate: 10, fields: {} }} }}",
+                fields
+            );
+            Mission::parse(&text, "test.yaml", &decoder)
+        };
+
+        assert!(phase("[0x04]").is_ok());
+        let error = phase("[0x05]").err().unwrap();
+        assert!(error.to_string().contains("tilt isn't streamed"));
+    }
+
+    #[cfg(feature = "virtual")]
+    mod entry {
+        use std::{
+            io::{Read, Write},
+            sync::Arc,
+        };
+
+        use serialport::SerialPort;
+
+        use super::*;
+        use crate::{
+            clock::VirtualClock,
+            mip::{self, RawField, Scanner},
+            virtual_port::{ScriptedDevice, VirtualPort},
+        };
+
+        const TIMEOUT: Duration = Duration::from_millis(100);
+
+        fn device(accel_z: f32) -> ScriptedDevice {
+            let accel = [0.0, 0.0, accel_z]
+                .iter()
+                .flat_map(|v: &f32| v.to_be_bytes().to_vec())
+                .collect();
+            let frame = Frame::new(mip::IMU_DATA, vec![RawField::new(0x04, accel)]);
+            ScriptedDevice::new().stream(frame, Duration::from_millis(10))
+        }
+
+        /// Frames that have arrived on `port` so far, without waiting for more.
+        fn packets(mut port: VirtualPort) -> impl FnMut() -> Result<Option<Frame>, Error> {
+            let mut scanner = Scanner::new();
+            move || {
+                let waiting = port.bytes_to_read()? as usize;
+                let mut buffer = vec![0; waiting];
+                port.read_exact(&mut buffer)?;
+                scanner.push(&buffer);
+                Ok(scanner.next_frame().map(|(frame, _)| frame))
+            }
+        }
+
+        fn wait(
+            port: VirtualPort,
+            clock: &VirtualClock,
+            condition: &str,
+        ) -> Result<Duration, Error> {
+            let mut decoder = Decoder::new(false, Derived::default());
+            let expr = Expr::parse(condition)?;
+            let start = clock.now();
+            wait_for(
+                &mut packets(port),
+                &mut decoder,
+                clock,
+                &expr,
+                Some(TIMEOUT),
+                &AtomicBool::new(false),
+            )?;
+            Ok(clock.now() - start)
+        }
+
+        #[test]
+        fn met_by_new_data() {
+            let clock = VirtualClock::new();
+            let port = VirtualPort::scripted(device(1.0), Arc::new(clock.clone()));
+
+            let waited = wait(port, &clock, "imu.accel_z > 0").unwrap();
+            assert!(waited < TIMEOUT);
+        }
+
+        #[test]
+        fn times_out_on_the_clock() {
+            let clock = VirtualClock::new();
+            let port = VirtualPort::scripted(device(-1.0), Arc::new(clock.clone()));
+
+            let start = clock.now();
+            let error = wait(port, &clock, "imu.accel_z > 0").unwrap_err();
+            assert_eq!(error.to_string(), "wasn't met in time");
+            assert!(clock.now() - start >= TIMEOUT);
+        }
+
+        #[test]
+        fn stale_packets_are_drained() {
+            let clock = VirtualClock::new();
+            let mut port = VirtualPort::scripted(device(1.0), Arc::new(clock.clone()));
+
+            // Data from the previous phase arrives, then the stream is turned off
+            clock.advance(Duration::from_millis(50));
+            assert!(port.bytes_to_read().unwrap() > 0);
+            let idle = mip::command(mip::SET_IDLE, vec![]).encode().unwrap();
+            port.write_all(&idle).unwrap();
+
+            let error = wait(port, &clock, "imu.accel_z > 0").unwrap_err();
+            assert_eq!(error.to_string(), "wasn't met in time");
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use clap::ArgMatches;
+use lordserial::parser::Lord;
+
+use crate::{
+    capture, channels::Decoder, config::Config, expr::Expr, mip::Frame, partial,
+    time::TimeFormatter, Error,
+};
+
+const BELL: &str = "\x07";
+const ALARM_STYLE: &str = "\x1b[1;97;41m";
+const RESET_STYLE: &str = "\x1b[0m";
+
+struct Alarm {
+    source: String,
+    expr: Expr,
+    active: bool,
+}
+
+pub fn run(
+    lord: &mut Lord,
+    matches: &ArgMatches,
+    config: &Config,
+    times: &TimeFormatter,
+) -> Result<(), Error> {
+    let mut alarms = Vec::new();
+    for source in matches.values_of("alarm").into_iter().flatten() {
+        alarms.push(Alarm {
+            source: source.to_string(),
+            expr: Expr::parse(source)?,
+            active: false,
+        });
+    }
+
+    // Show everything the alarms depend on, plus anything asked for explicitly
+    let mut shown: Vec<String> = matches
+        .values_of("channel")
+        .into_iter()
+        .flatten()
+        .map(String::from)
+        .collect();
+    for alarm in &alarms {
+        for name in alarm.expr.channels() {
+            if !shown.iter().any(|s| s == name) {
+                shown.push(name.to_string());
+            }
+        }
+    }
+
+    let mut decoder = Decoder::from_matches(matches, config)?;
+    for name in &shown {
+        if !decoder.is_known(name) {
+            return Err(format!("Unknown channel {}", name).into());
+        }
+    }
+
+    let interval = Duration::from_millis(matches.value_of("interval").unwrap().parse()?);
+    let beep = matches.is_present("beep");
+    let mut latest: HashMap<String, f64> = HashMap::new();
+    let stop = partial::stop_flag()?;
+    let mut last_print = Instant::now();
+
+    loop {
+        // Output is often piped to a log, make sure it ends with everything seen
+        if stop.load(Ordering::Relaxed) {
+            print_status(&shown, &latest, &alarms, times, beep)?;
+            io::stdout().flush()?;
+            return Ok(());
+        }
+
+        if let Some(data) = lord.get_data() {
+            let decoded = decoder.decode(&Frame::from_packet(&data)?)?;
+            for unknown in decoded.unknown.iter().filter(|u| decoder.is_first(u)) {
+                eprintln!("{} {}", times.format(capture::timestamp()), unknown);
+            }
+            latest.extend(decoded.values);
+
+            for alarm in &mut alarms {
+                let active = match alarm.expr.eval(&|name| latest.get(name).copied()) {
+                    Some(value) => value != 0.0,
+                    None => continue,
+                };
+
+                if active != alarm.active {
+                    alarm.active = active;
+                    let time = times.format(capture::timestamp());
+                    if active {
+                        println!(
+                            "{} {}ALARM{} {}{}",
+                            time,
+                            ALARM_STYLE,
+                            RESET_STYLE,
+                            alarm.source,
+                            if beep { BELL } else { "" }
+                        );
+                    } else {
+                        println!("{} clear {}", time, alarm.source);
+                    }
+                }
+            }
+        }
+
+        if last_print.elapsed() >= interval {
+            last_print = Instant::now();
+            print_status(&shown, &latest, &alarms, times, beep)?;
+        }
+    }
+}
+
+fn print_status(
+    shown: &[String],
+    latest: &HashMap<String, f64>,
+    alarms: &[Alarm],
+    times: &TimeFormatter,
+    beep: bool,
+) -> Result<(), Error> {
+    let mut line = times.format(capture::timestamp());
+    for name in shown {
+        match latest.get(name) {
+            Some(value) => line.push_str(&format!(" {}={:.4}", name, value)),
+            None => line.push_str(&format!(" {}=-", name)),
+        }
+    }
+
+    let active: Vec<&str> = alarms
+        .iter()
+        .filter(|a| a.active)
+        .map(|a| a.source.as_str())
+        .collect();
+
+    let mut stdout = io::stdout();
+    if active.is_empty() {
+        writeln!(stdout, "{}", line)?;
+    } else {
+        // Keep beeping for as long as anything is out of bounds
+        writeln!(
+            stdout,
+            "{} {}[{}]{}{}",
+            line,
+            ALARM_STYLE,
+            active.join("; "),
+            RESET_STYLE,
+            if beep { BELL } else { "" }
+        )?;
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Output files are written under a `.partial` name next to where they'll end up and only
+//! renamed into place once they're finished, so a file with the real name is always
+//! complete and anything left behind by a crash or power loss is easy to spot and `recover`.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant},
+};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use crate::Error;
+
+pub const SUFFIX: &str = ".partial";
+
+// Bounds how much a power loss can take with it, flushing only reaches the OS
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where `path` is written until it's finished.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(SUFFIX);
+    PathBuf::from(name)
+}
+
+/// The path a `.partial` file would have been renamed to, `None` if it isn't one.
+pub fn final_path(partial: &Path) -> Option<PathBuf> {
+    partial.to_str()?.strip_suffix(SUFFIX).map(PathBuf::from)
+}
+
+/// Whether writing `path` would truncate `other`, which has to be refused while `other` is
+/// still being read.
+pub fn writes_over(path: &Path, other: &Path) -> bool {
+    match (
+        fs::canonicalize(partial_path(path)),
+        fs::canonicalize(other),
+    ) {
+        (Ok(partial), Ok(other)) => partial == other,
+        _ => false,
+    }
+}
+
+/// Set on Ctrl-C or SIGTERM instead of exiting, so commands that write until they're
+/// stopped can finish their files rather than leave them `.partial`.
+pub fn stop_flag() -> Result<Arc<AtomicBool>, Error> {
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in &[SIGINT, SIGTERM] {
+        signal_hook::flag::register(*signal, stop.clone())?;
+    }
+    Ok(stop)
+}
+
+#[derive(Debug)]
+pub struct PartialFile {
+    file: File,
+    partial: PathBuf,
+    path: PathBuf,
+    last_sync: Instant,
+}
+
+impl PartialFile {
+    /// Opened for reading as well, for writers that go back over what they've written.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let partial = partial_path(&path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&partial)?;
+
+        Ok(PartialFile {
+            file,
+            partial,
+            path,
+            last_sync: Instant::now(),
+        })
+    }
+
+    /// Another handle to the file, for writers that want to own one.
+    pub fn try_clone(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// Get what's been written onto the disk, if it's been a while since the last time.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Sync and move the file to its real name.
+    pub fn commit(self) -> Result<PathBuf, Error> {
+        self.file.sync_all()?;
+        fs::rename(&self.partial, &self.path)?;
+
+        // The rename itself only survives a power loss once the directory is synced
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+
+        Ok(self.path)
+    }
+}
+
+impl Write for PartialFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
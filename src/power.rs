@@ -0,0 +1,180 @@
+//! Watches external power sources so supply changes can be annotated in recordings.
+//!
+//! Power can be read from a GPIO line (sysfs value file, high while powered) or from a
+//! Network UPS Tools daemon.
+
+use std::{
+    fmt, fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+    thread,
+    time::Duration,
+};
+
+use crate::Error;
+
+const NUT_PORT: u16 = 3493;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    On,
+    Lost,
+    OnBattery,
+    LowBattery,
+}
+
+impl PowerState {
+    /// Whether a recording should be closed out when this state is seen.
+    pub fn is_critical(self) -> bool {
+        matches!(self, PowerState::Lost | PowerState::LowBattery)
+    }
+}
+
+impl fmt::Display for PowerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PowerState::On => "on",
+            PowerState::Lost => "lost",
+            PowerState::OnBattery => "on-battery",
+            PowerState::LowBattery => "low-battery",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PowerEvent {
+    pub source: String,
+    pub state: PowerState,
+}
+
+impl fmt::Display for PowerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "power source={} state={}", self.source, self.state)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Source {
+    Gpio { path: PathBuf, active_low: bool },
+    Ups { name: String, address: String },
+}
+
+impl Source {
+    /// GPIO by number (exported through sysfs) or by path to its value file.
+    pub fn gpio(line: &str, active_low: bool) -> Self {
+        let path = match line.parse::<u32>() {
+            Ok(number) => PathBuf::from(format!("/sys/class/gpio/gpio{}/value", number)),
+            Err(_) => PathBuf::from(line),
+        };
+
+        Source::Gpio { path, active_low }
+    }
+
+    /// UPS as `name@host[:port]`, following upsc.
+    pub fn ups(spec: &str) -> Result<Self, Error> {
+        let (name, host) = match spec.find('@') {
+            Some(i) => (&spec[..i], &spec[i + 1..]),
+            None => return Err(format!("Expected UPS as name@host, got {}", spec).into()),
+        };
+
+        let address = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, NUT_PORT)
+        };
+
+        Ok(Source::Ups {
+            name: name.to_string(),
+            address,
+        })
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Source::Gpio { path, .. } => format!("gpio:{}", path.display()),
+            Source::Ups { name, address } => format!("ups:{}@{}", name, address),
+        }
+    }
+}
+
+/// Poll `source` every `interval`, sending an event whenever its state changes.
+pub fn watch(source: Source, interval: Duration, events: Sender<PowerEvent>) {
+    thread::spawn(move || {
+        let mut ups = None;
+        let mut last = None;
+
+        loop {
+            let state = match &source {
+                Source::Gpio { path, active_low } => read_gpio(path, *active_low),
+                Source::Ups { name, address } => read_ups(&mut ups, name, address),
+            };
+
+            match state {
+                Ok(state) if last != Some(state) => {
+                    last = Some(state);
+                    let event = PowerEvent {
+                        source: source.name(),
+                        state,
+                    };
+
+                    if events.send(event).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    eprintln!("Failed to read power state from {}: {}", source.name(), e);
+                    ups = None;
+                }
+            }
+
+            thread::sleep(interval);
+        }
+    });
+}
+
+fn read_gpio(path: &Path, active_low: bool) -> Result<PowerState, Error> {
+    let high = fs::read_to_string(path)?.trim() == "1";
+
+    Ok(if high != active_low {
+        PowerState::On
+    } else {
+        PowerState::Lost
+    })
+}
+
+fn read_ups(
+    connection: &mut Option<BufReader<TcpStream>>,
+    name: &str,
+    address: &str,
+) -> Result<PowerState, Error> {
+    if connection.is_none() {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        *connection = Some(BufReader::new(stream));
+    }
+
+    let connection = connection.as_mut().unwrap();
+    writeln!(connection.get_mut(), "GET VAR {} ups.status", name)?;
+
+    let mut line = String::new();
+    connection.read_line(&mut line)?;
+
+    // VAR <ups> ups.status "OL CHRG"
+    let status = match line.split('"').nth(1) {
+        Some(status) => status,
+        None => return Err(format!("Unexpected reply from upsd: {}", line.trim()).into()),
+    };
+
+    let flags: Vec<&str> = status.split_whitespace().collect();
+    Ok(if flags.contains(&"LB") {
+        PowerState::LowBattery
+    } else if flags.contains(&"OB") {
+        PowerState::OnBattery
+    } else {
+        PowerState::On
+    })
+}
@@ -0,0 +1,139 @@
+use crate::{
+    device::{self, DataSet},
+    mip::{self, Command},
+};
+
+pub struct Format {
+    pub set: u8,
+    /// Target rate in Hz, turned into a decimation from the device's base rate
+    pub rate: u16,
+    pub fields: &'static [u8],
+}
+
+pub struct Profile {
+    pub name: &'static str,
+    /// Fragments of the model name this profile applies to
+    pub models: &'static [&'static str],
+    pub formats: &'static [Format],
+}
+
+const IMU: Format = Format {
+    set: mip::IMU_DATA,
+    rate: 100,
+    // Scaled accel, scaled gyro, delta theta, delta velocity, GPS timestamp
+    fields: &[0x04, 0x05, 0x07, 0x08, 0x12],
+};
+
+const GNSS: Format = Format {
+    set: mip::GNSS_DATA,
+    rate: 4,
+    // LLH position, NED velocity, GPS time, fix info
+    fields: &[0x03, 0x05, 0x09, 0x0B],
+};
+
+const FILTER: Format = Format {
+    set: mip::FILTER_DATA,
+    rate: 50,
+    // LLH position, NED velocity, quaternion, euler angles, status, GPS timestamp
+    fields: &[0x01, 0x02, 0x03, 0x05, 0x10, 0x11],
+};
+
+/// The usual fields for each data set, for when only a rate is asked for.
+pub const DEFAULT_FORMATS: &[Format] = &[IMU, GNSS, FILTER];
+
+pub const PROFILES: &[Profile] = &[
+    Profile {
+        name: "GNSS/INS",
+        models: &["GX5-45", "GX4-45", "GX3-45", "GQ7"],
+        formats: &[IMU, GNSS, FILTER],
+    },
+    Profile {
+        name: "AHRS",
+        models: &["GX5-25", "GX5-35", "GX4-25", "CV5-25", "CX5-25"],
+        formats: &[IMU, FILTER],
+    },
+    Profile {
+        name: "VRU",
+        models: &["GX5-15", "CV5-15", "CX5-15"],
+        formats: &[IMU, FILTER],
+    },
+    // Fallback for anything we don't recognise, every device has an IMU
+    Profile {
+        name: "IMU",
+        models: &[],
+        formats: &[IMU],
+    },
+];
+
+pub fn for_model(model_name: &str) -> &'static Profile {
+    PROFILES
+        .iter()
+        .find(|p| p.models.iter().any(|m| model_name.contains(m)))
+        .unwrap_or(&PROFILES[PROFILES.len() - 1])
+}
+
+/// The data sets whose base rates `rate` prints.
+pub const RATE: &[DataSet] = &[device::IMU, device::GNSS];
+
+/// The fixed formats `configure` applies, as (field descriptor, decimation) pairs.
+pub const CONFIGURE: &[(DataSet, &[(u8, u16)])] = &[
+    (
+        device::IMU,
+        &[(0x06, 50), (0x04, 50), (0x05, 50), (0x0A, 50), (0x17, 50)],
+    ),
+    (
+        device::GNSS,
+        &[(0x09, 5), (0x0B, 5), (0x03, 5), (0x07, 5), (0x04, 5)],
+    ),
+];
+
+/// The fixed formats `ekf` applies before turning on filter auto-initialization.
+pub const EKF: &[(DataSet, &[(u8, u16)])] = &[
+    (device::FILTER, &[(0x01, 50), (0x11, 50)]),
+    (device::GNSS, &[(0x03, 4), (0x09, 4)]),
+];
+
+/// The fields of the single packet `packet` sends, all in the 3DM descriptor set.
+#[rustfmt::skip]
+pub const PACKET: &[(Command, &[u8])] = &[
+    // Write IMU, GNSS and filter formats, five fields each as descriptor and decimation
+    (mip::IMU_FORMAT, &[
+        mip::APPLY, 0x05,
+        0x17, 0x00, 0x0A,
+        0x06, 0x00, 0x0A,
+        0x04, 0x00, 0x0A,
+        0x05, 0x00, 0x0A,
+        0x0A, 0x00, 0x0A,
+    ]),
+    (mip::GNSS_FORMAT, &[
+        mip::APPLY, 0x05,
+        0x09, 0x00, 0x01,
+        0x0B, 0x00, 0x01,
+        0x03, 0x00, 0x01,
+        0x07, 0x00, 0x01,
+        0x05, 0x00, 0x01,
+    ]),
+    (mip::FILTER_FORMAT, &[
+        mip::APPLY, 0x05,
+        0x11, 0x00, 0x0A,
+        0x01, 0x00, 0x0A,
+        0x02, 0x00, 0x0A,
+        0x03, 0x00, 0x0A,
+        0x10, 0x00, 0x0A,
+    ]),
+    // Save them
+    (mip::IMU_FORMAT, &[mip::SAVE]),
+    (mip::GNSS_FORMAT, &[mip::SAVE]),
+    (mip::FILTER_FORMAT, &[mip::SAVE]),
+    // Enable the IMU, GNSS and filter streams and save that for startup
+    (mip::ENABLE_STREAM, &[mip::APPLY, 0x01, 0x01]),
+    (mip::ENABLE_STREAM, &[mip::APPLY, 0x02, 0x01]),
+    (mip::ENABLE_STREAM, &[mip::APPLY, 0x03, 0x01]),
+    (mip::ENABLE_STREAM, &[mip::SAVE, 0x01]),
+    (mip::ENABLE_STREAM, &[mip::SAVE, 0x02]),
+    (mip::ENABLE_STREAM, &[mip::SAVE, 0x03]),
+    // Filter fields, though they go out in the 3DM set with the rest
+    (Command { set: 0x0C, field: 0x0D }, &[]),
+    (Command { set: 0x0C, field: 0x19 }, &[0x02]),
+    (Command { set: 0x0C, field: 0x19 }, &[mip::SAVE, 0x01]),
+];
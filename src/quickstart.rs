@@ -0,0 +1,137 @@
+use std::{collections::HashMap, time::Duration};
+
+use clap::ArgMatches;
+use lordserial::parser::Lord;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    device::{self, DataSet},
+    profile, Error,
+};
+
+// Fraction of the expected rate a stream has to reach to count as healthy
+const RATE_TOLERANCE: f64 = 0.9;
+
+pub fn run(lord: &mut Lord, matches: &ArgMatches) -> Result<(), Error> {
+    let duration = matches.value_of("duration").unwrap();
+    let duration = match duration.parse() {
+        Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+        _ => {
+            return Err(format!(
+                "Invalid duration {}, give a whole number of seconds",
+                duration
+            )
+            .into())
+        }
+    };
+    let clock = SystemClock::new();
+
+    let info = device::device_info(lord)?;
+    let profile = profile::for_model(&info.model_name);
+    println!(
+        "Found {} (SN {}), applying {} profile",
+        info.model_name, info.serial_number, profile.name
+    );
+
+    let mut expected = Vec::new();
+    for set in device::DATA_SETS.iter() {
+        let format = profile.formats.iter().find(|f| f.set == set.descriptor);
+        let format = match format {
+            Some(format) => format,
+            None => {
+                // Not every model has every data set, so a failure here is expected
+                device::enable_stream(lord, *set, false).ok();
+                continue;
+            }
+        };
+
+        let base_rate = device::base_rate(lord, *set)?;
+        let decimation = (base_rate / format.rate).max(1);
+        let fields: Vec<(u8, u16)> = format.fields.iter().map(|f| (*f, decimation)).collect();
+
+        device::set_format(lord, *set, &fields)?;
+        device::enable_stream(lord, *set, true)?;
+        if matches.is_present("save") {
+            device::save(lord, *set)?;
+        }
+
+        expected.push((*set, base_rate as f64 / decimation as f64));
+    }
+
+    println!("Verifying data for {}s...", duration.as_secs());
+    let counts = count_packets(lord, &clock, duration);
+
+    println!();
+    println!("Device     {} ({})", info.model_name, info.model_number);
+    println!("Serial     {}", info.serial_number);
+    println!("Firmware   {}", info.firmware_version());
+    println!("Options    {}", info.options);
+    println!("Profile    {}", profile.name);
+    if matches.is_present("save") {
+        println!("Settings   saved as startup configuration");
+    }
+    println!();
+    println!(
+        "{:<8} {:>10} {:>10}  Status",
+        "Stream", "Expected", "Measured"
+    );
+
+    let mut healthy = true;
+    for (set, rate) in &expected {
+        let measured =
+            counts.get(&set.descriptor).copied().unwrap_or(0) as f64 / duration.as_secs_f64();
+        let status = status(*rate, measured);
+        healthy &= status == "OK";
+
+        println!(
+            "{:<8} {:>7.1} Hz {:>7.1} Hz  {}",
+            set.name, rate, measured, status
+        );
+    }
+    println!();
+
+    if healthy {
+        println!("Data is flowing at the expected rates");
+        Ok(())
+    } else {
+        Err(missing_streams(&expected, &counts).into())
+    }
+}
+
+fn status(expected: f64, measured: f64) -> &'static str {
+    if measured == 0.0 {
+        "MISSING"
+    } else if measured < expected * RATE_TOLERANCE {
+        "LOW"
+    } else {
+        "OK"
+    }
+}
+
+fn missing_streams(expected: &[(DataSet, f64)], counts: &HashMap<u8, usize>) -> String {
+    let names: Vec<&str> = expected
+        .iter()
+        .filter(|(set, _)| !counts.contains_key(&set.descriptor))
+        .map(|(set, _)| set.name)
+        .collect();
+
+    if names.is_empty() {
+        "Data is not flowing at the expected rates".to_string()
+    } else {
+        format!("No data received for {}", names.join(", "))
+    }
+}
+
+/// Count the packets received for each descriptor set over `duration`.
+pub fn count_packets(lord: &mut Lord, clock: &dyn Clock, duration: Duration) -> HashMap<u8, usize> {
+    let mut counts = HashMap::new();
+    let start = clock.now();
+
+    while clock.now() - start < duration {
+        if let Some(data) = lord.get_data() {
+            *counts.entry(data.header.descriptor).or_insert(0) += 1;
+        }
+    }
+
+    counts
+}
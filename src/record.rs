@@ -0,0 +1,280 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::ArgMatches;
+use desert::ToBytes;
+use lordserial::parser::Lord;
+
+use crate::{
+    blackbox::{self, BlackBox},
+    capture::{self, CaptureWriter, Kind},
+    channels::{Decoded, Decoder},
+    config::Config,
+    convert,
+    feather::FeatherWriter,
+    mip::{Command, Frame},
+    partial,
+    power::{self, Source},
+    time::TimeFormatter,
+    uplink::{self, Uplink},
+    Error,
+};
+
+// Bounds how much data is lost if the process is killed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub const FORMATS: &[&str] = &["capture", "feather"];
+
+/// What recording sends, which is only what `--uplink` does.
+pub const COMMANDS: &[Command] = uplink::COMMANDS;
+
+enum Output {
+    Capture(CaptureWriter),
+    BlackBox(BlackBox),
+    // Decoded channels, the raw bytes of unknown fields and events
+    Feather(FeatherWriter),
+}
+
+impl Output {
+    fn write(&mut self, kind: Kind, time: u64, data: &[u8]) -> Result<(), Error> {
+        match self {
+            Output::Capture(writer) => writer.write(kind, time, data),
+            Output::BlackBox(ring) => ring.write(kind, time, data),
+            Output::Feather(writer) => match kind {
+                Kind::Event => writer.write_event(time, &String::from_utf8_lossy(data)),
+                // Decoded by write_values
+                Kind::Packet => Ok(()),
+            },
+        }
+    }
+
+    fn write_values(&mut self, time: u64, set: u8, decoded: &Decoded) -> Result<(), Error> {
+        match self {
+            Output::Feather(writer) => {
+                writer.write(time, set, &decoded.values)?;
+                for unknown in &decoded.unknown {
+                    writer.write_unknown(time, unknown)?;
+                }
+                Ok(())
+            }
+            // The raw packet has everything
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Output::Capture(writer) => writer.flush(),
+            Output::BlackBox(ring) => ring.sync(),
+            Output::Feather(writer) => writer.flush(),
+        }
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self {
+            Output::Capture(writer) => writer.finish(),
+            Output::BlackBox(mut ring) => {
+                println!("Black box frozen to {}", ring.freeze()?.display());
+                ring.finish()?;
+                Ok(())
+            }
+            Output::Feather(writer) => {
+                for path in writer.finish()? {
+                    println!("Wrote {}", path.display());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Reasons to freeze the black box: SIGUSR1, a trigger file appearing or the data stalling.
+struct Triggers {
+    signal: Arc<AtomicBool>,
+    file: Option<PathBuf>,
+    stall: Option<Duration>,
+    stalled: bool,
+}
+
+impl Triggers {
+    fn new(matches: &ArgMatches) -> Result<Self, Error> {
+        let signal = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, signal.clone())?;
+
+        let stall: u64 = matches.value_of("stall-timeout").unwrap().parse()?;
+
+        Ok(Triggers {
+            signal,
+            file: matches.value_of("trigger-file").map(PathBuf::from),
+            stall: Some(Duration::from_secs(stall)).filter(|d| *d > Duration::from_secs(0)),
+            stalled: false,
+        })
+    }
+
+    fn check(&mut self, last_packet: Instant) -> Option<&'static str> {
+        if self.signal.swap(false, Ordering::Relaxed) {
+            return Some("signal");
+        }
+
+        if let Some(file) = &self.file {
+            if file.exists() {
+                fs::remove_file(file).ok();
+                return Some("trigger-file");
+            }
+        }
+
+        // Only trip once per stall, data has to come back before it can trip again
+        let stalled = self.stall.is_some_and(|s| last_packet.elapsed() > s);
+        let tripped = stalled && !self.stalled;
+        self.stalled = stalled;
+        if tripped {
+            Some("stall")
+        } else {
+            None
+        }
+    }
+}
+
+pub fn run(
+    lord: &mut Lord,
+    matches: &ArgMatches,
+    config: &Config,
+    times: &TimeFormatter,
+) -> Result<(), Error> {
+    let path = matches.value_of("OUTPUT").unwrap();
+
+    let format = match matches.value_of("format") {
+        Some(format) => format,
+        None if convert::format_of(path) == Some("feather") => "feather",
+        None => "capture",
+    };
+
+    let mut decoder = Decoder::from_matches(matches, config)?;
+    let mut triggers = None;
+    let mut output = if matches.is_present("blackbox") {
+        if format == "feather" {
+            return Err("The black box can only record capture files".into());
+        }
+
+        let size = blackbox::parse_size(matches.value_of("size").unwrap())?;
+        freeze_leftover(Path::new(path))?;
+        triggers = Some(Triggers::new(matches)?);
+        println!("Recording to {} byte black box {}", size, path);
+        Output::BlackBox(BlackBox::create(Path::new(path), size)?)
+    } else if format == "feather" {
+        println!("Recording channels to Arrow IPC files next to {}", path);
+        Output::Feather(FeatherWriter::create(path, &decoder.derived_channels())?)
+    } else {
+        println!("Recording to {}", path);
+        Output::Capture(CaptureWriter::create(path)?)
+    };
+
+    let (events, power_events) = mpsc::channel();
+    let interval = Duration::from_millis(matches.value_of("power-interval").unwrap().parse()?);
+    if let Some(line) = matches.value_of("power-gpio") {
+        let source = Source::gpio(line, matches.is_present("power-active-low"));
+        power::watch(source, interval, events.clone());
+    }
+    if let Some(ups) = matches.value_of("ups") {
+        power::watch(Source::ups(ups)?, interval, events.clone());
+    }
+
+    let mut uplink = Uplink::from_matches(lord, matches, decoder.derived_channels())?;
+    let shutdown = matches.is_present("power-shutdown");
+    let stop = partial::stop_flag()?;
+    let mut packets = 0u64;
+    let mut last_flush = Instant::now();
+    let mut last_packet = Instant::now();
+
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            output.finish()?;
+            println!("Recording stopped after {} packets", packets);
+            return Ok(());
+        }
+
+        while let Ok(event) = power_events.try_recv() {
+            let time = capture::timestamp();
+            eprintln!("{} {}", times.format(time), event);
+            output.write(Kind::Event, time, event.to_string().as_bytes())?;
+
+            if shutdown && event.state.is_critical() {
+                output.finish()?;
+                println!(
+                    "Power {}, recording stopped after {} packets",
+                    event.state, packets
+                );
+                return Ok(());
+            }
+        }
+
+        if let Some(data) = lord.get_data() {
+            let time = capture::timestamp();
+            let bytes = data.to_bytes()?;
+
+            // The raw packet is kept regardless, this just makes new fields easy to spot
+            if let Some(frame) = Frame::parse(&bytes) {
+                let decoded = decoder.decode(&frame)?;
+                for unknown in decoded.unknown.iter().filter(|u| decoder.is_first(u)) {
+                    eprintln!("{} {}", times.format(time), unknown);
+                    output.write(Kind::Event, time, unknown.to_string().as_bytes())?;
+                }
+                output.write_values(time, frame.descriptor, &decoded)?;
+                if let Some(uplink) = &mut uplink {
+                    uplink.add(frame.descriptor, &decoded.values);
+                }
+            }
+
+            output.write(Kind::Packet, time, &bytes)?;
+            packets += 1;
+            last_packet = Instant::now();
+        }
+
+        if let Some(uplink) = &mut uplink {
+            uplink.tick();
+        }
+
+        if let (Some(triggers), Output::BlackBox(ring)) = (&mut triggers, &mut output) {
+            if let Some(reason) = triggers.check(last_packet) {
+                let time = capture::timestamp();
+                let event = format!("blackbox trigger={}", reason);
+                eprintln!("{} {}", times.format(time), event);
+
+                ring.write(Kind::Event, time, event.as_bytes())?;
+                println!("Black box frozen to {}", ring.freeze()?.display());
+            }
+        }
+
+        if last_flush.elapsed() >= FLUSH_INTERVAL {
+            output.flush()?;
+            last_flush = Instant::now();
+        }
+    }
+}
+
+/// A ring that's still `.partial` was left by a run that didn't exit cleanly, which is
+/// exactly the data worth keeping, so freeze it before it gets overwritten.
+fn freeze_leftover(path: &Path) -> Result<(), Error> {
+    let path = partial::partial_path(path);
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let mut ring = BlackBox::open(&path)?;
+    if !ring.is_empty() {
+        println!(
+            "Froze black box left by the previous run to {}",
+            ring.freeze()?.display()
+        );
+    }
+
+    Ok(())
+}
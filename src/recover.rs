@@ -0,0 +1,186 @@
+//! Salvage what can still be read from output files left behind by a run that crashed or
+//! lost power, usually `.partial` files that never got their real name.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use clap::ArgMatches;
+
+use crate::{
+    capture::{self, CaptureReader, CaptureWriter, Kind},
+    feather, partial, Error,
+};
+
+pub fn run(matches: &ArgMatches) -> Result<(), Error> {
+    let input = Path::new(matches.value_of("INPUT").unwrap());
+    let output = match matches.value_of("OUTPUT") {
+        Some(output) => PathBuf::from(output),
+        None => default_output(input),
+    };
+    recover(input, &output)?;
+
+    println!(
+        "{} can be deleted once {} has been checked",
+        input.display(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// The writers work on `output.partial` until they finish, which is `input` itself when
+/// `run.cap.partial` goes back to `run.cap`, so the output is written under a staging name
+/// first and only takes its own once `input` has been read to the end.
+fn recover(input: &Path, output: &Path) -> Result<(), Error> {
+    if output.exists() {
+        return Err(format!("{} already exists, give another OUTPUT", output.display()).into());
+    }
+
+    let mut magic = [0u8; 8];
+    File::open(input)?
+        .read_exact(&mut magic)
+        .map_err(|_| format!("{} is too short to recover anything from", input.display()))?;
+
+    let staging = staging_path(output);
+    if &magic == capture::MAGIC {
+        recover_capture(input, &staging, output)?;
+    } else if magic.starts_with(feather::MAGIC) {
+        let rows = feather::recover(input, &staging)?;
+        println!(
+            "Recovered {} rows from {} to {}",
+            rows,
+            input.display(),
+            output.display()
+        );
+    } else {
+        return Err(format!(
+            "{} isn't a capture or Arrow IPC file, nothing to recover",
+            input.display()
+        )
+        .into());
+    }
+
+    fs::rename(&staging, output)?;
+    Ok(())
+}
+
+/// Where the output is written until `input` has been read.
+fn staging_path(output: &Path) -> PathBuf {
+    let mut name = OsString::from(output.as_os_str());
+    name.push(".recovering");
+    PathBuf::from(name)
+}
+
+/// `run.cap.partial` goes back to `run.cap`, anything else gets `.recovered` added.
+fn default_output(input: &Path) -> PathBuf {
+    match partial::final_path(input) {
+        Some(path) => path,
+        None => {
+            let stem = input
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let name = match input.extension() {
+                Some(ext) => format!("{}.recovered.{}", stem, ext.to_string_lossy()),
+                None => format!("{}.recovered", stem),
+            };
+            input.with_file_name(name)
+        }
+    }
+}
+
+/// Write what can be read of `input` to `staging`, to be renamed to `output`.
+fn recover_capture(input: &Path, staging: &Path, output: &Path) -> Result<(), Error> {
+    if partial::writes_over(staging, input) {
+        return Err(format!("Recovering {} would write over it", input.display()).into());
+    }
+    let mut reader = CaptureReader::open(input)?;
+    let mut writer = CaptureWriter::create(staging)?;
+    let mut packets = 0u64;
+    let mut events = 0u64;
+
+    // Everything up to the first record that can't be read, usually the one being written
+    // when the run died
+    let damaged = loop {
+        match reader.next_record() {
+            Ok(Some(record)) => {
+                writer.write(record.kind, record.time, &record.data)?;
+                match record.kind {
+                    Kind::Packet => packets += 1,
+                    Kind::Event => events += 1,
+                }
+            }
+            Ok(None) => break None,
+            Err(e) => break Some(e),
+        }
+    };
+    writer.finish()?;
+
+    println!(
+        "Recovered {} packets and {} events from {} to {}",
+        packets,
+        events,
+        input.display(),
+        output.display()
+    );
+    if let Some(e) = damaged {
+        println!("Stopped at a damaged record: {}", e);
+    } else if reader.is_complete() {
+        println!("{} was already complete, nothing was lost", input.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn records(path: &Path) -> (usize, bool) {
+        let mut reader = CaptureReader::open(path).unwrap();
+        let mut count = 0;
+        while reader.next_record().unwrap().is_some() {
+            count += 1;
+        }
+        (count, reader.is_complete())
+    }
+
+    #[test]
+    fn partial_captures_go_back_to_their_own_name() {
+        let dir = env::temp_dir().join(format!("lordcli-recover-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("x.cap");
+        let input = partial::partial_path(&output);
+
+        // A run that died without finishing its capture
+        let mut writer = CaptureWriter::create(&output).unwrap();
+        for i in 0..2000u64 {
+            writer
+                .write(Kind::Packet, i, &[0x75, 0x65, 0x80, 0x00])
+                .unwrap();
+        }
+        writer.write(Kind::Event, 2000, b"stopped").unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        let source = fs::read(&input).unwrap();
+
+        // The staging file is never the input, so it can't truncate it
+        assert!(!partial::writes_over(&staging_path(&output), &input));
+        assert!(partial::writes_over(&output, &input));
+        assert!(recover_capture(&input, &output, &output).is_err());
+        assert_eq!(fs::read(&input).unwrap(), source);
+
+        assert_eq!(default_output(&input), output);
+        recover(&input, &output).unwrap();
+        assert_eq!(records(&output), (2001, true));
+        assert_eq!(fs::read(&input).unwrap(), source);
+        assert!(!staging_path(&output).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+//! Saved startup settings: dumping them to a TOML baseline and auditing devices against one,
+//! to catch units that have drifted from the rest of a fleet.
+//!
+//! ```toml
+//! model = "3DM-GX5-45"
+//!
+//! [imu]
+//! stream = true
+//! # [descriptor, decimation]
+//! format = [[0x04, 10], [0x05, 10]]
+//! ```
+//!
+//! Data sets left out of the baseline aren't checked. The device can only report its startup
+//! settings by loading them, so the running settings are read first and put back after.
+
+use std::{fmt::Write as _, fs, io::Write as _};
+
+use clap::ArgMatches;
+use lordserial::parser::Lord;
+use serde::Deserialize;
+
+use crate::{
+    device::{self, DataSet},
+    partial::PartialFile,
+    Error,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Baseline {
+    model: Option<String>,
+    imu: Option<Startup>,
+    gnss: Option<Startup>,
+    filter: Option<Startup>,
+}
+
+impl Baseline {
+    fn get(&self, set: DataSet) -> Option<&Startup> {
+        match set.descriptor {
+            d if d == device::IMU.descriptor => self.imu.as_ref(),
+            d if d == device::GNSS.descriptor => self.gnss.as_ref(),
+            _ => self.filter.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Startup {
+    stream: bool,
+    format: Vec<(u8, u16)>,
+}
+
+fn read_startup(lord: &mut Lord, set: DataSet) -> Result<Startup, Error> {
+    let running = read_running(lord, set)?;
+    device::load_startup(lord, set)?;
+    let startup = read_running(lord, set);
+
+    device::set_format(lord, set, &running.format)
+        .and_then(|_| device::enable_stream(lord, set, running.stream))
+        .map_err(|e| format!("Failed to restore the running {} settings: {}", set.name, e))?;
+    startup
+}
+
+fn read_running(lord: &mut Lord, set: DataSet) -> Result<Startup, Error> {
+    let mut format = device::read_format(lord, set)?;
+    format.sort_unstable();
+
+    Ok(Startup {
+        stream: device::stream_enabled(lord, set)?,
+        format,
+    })
+}
+
+pub fn run(lord: &mut Lord, matches: &ArgMatches) -> Result<(), Error> {
+    if let Some(matches) = matches.subcommand_matches("dump") {
+        return dump(lord, matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("audit") {
+        return audit(lord, matches);
+    }
+
+    Err("Expected a config command, e.g. config audit".into())
+}
+
+fn dump(lord: &mut Lord, matches: &ArgMatches) -> Result<(), Error> {
+    let info = device::device_info(lord)?;
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# Startup settings of {} SN {}, firmware {}",
+        info.model_name,
+        info.serial_number,
+        info.firmware_version()
+    )?;
+    writeln!(out, "model = {:?}", info.model_name)?;
+
+    for set in device::DATA_SETS.iter() {
+        // Not every model has every data set
+        let startup = match read_startup(lord, *set) {
+            Ok(startup) => startup,
+            Err(_) => continue,
+        };
+
+        let format: Vec<String> = startup
+            .format
+            .iter()
+            .map(|(descriptor, decimation)| format!("[0x{:02X}, {}]", descriptor, decimation))
+            .collect();
+
+        writeln!(out)?;
+        writeln!(out, "[{}]", set.name.to_ascii_lowercase())?;
+        writeln!(out, "stream = {}", startup.stream)?;
+        writeln!(out, "# [descriptor, decimation]")?;
+        writeln!(out, "format = [{}]", format.join(", "))?;
+    }
+
+    match matches.value_of("OUTPUT") {
+        Some(path) => {
+            let mut file = PartialFile::create(path)?;
+            file.write_all(out.as_bytes())?;
+            file.commit()?;
+            println!("Wrote startup settings to {}", path);
+        }
+        None => print!("{}", out),
+    }
+
+    Ok(())
+}
+
+fn audit(lord: &mut Lord, matches: &ArgMatches) -> Result<(), Error> {
+    let path = matches.value_of("baseline").unwrap();
+    let baseline: Baseline = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+    let info = device::device_info(lord)?;
+    println!(
+        "Auditing {} SN {} against {}",
+        info.model_name, info.serial_number, path
+    );
+
+    let mut deviations = Vec::new();
+    if let Some(model) = &baseline.model {
+        if *model != info.model_name {
+            deviations.push(format!("model is {}, expected {}", info.model_name, model));
+        }
+    }
+
+    for set in device::DATA_SETS.iter() {
+        let expected = match baseline.get(*set) {
+            Some(expected) => expected,
+            None => continue,
+        };
+
+        let actual = match read_startup(lord, *set) {
+            Ok(actual) => actual,
+            Err(e) => {
+                deviations.push(format!("{} settings unreadable: {}", set.name, e));
+                continue;
+            }
+        };
+
+        deviations.extend(compare(*set, expected, &actual));
+    }
+
+    if deviations.is_empty() {
+        println!("Startup settings match the baseline");
+        return Ok(());
+    }
+
+    for deviation in &deviations {
+        println!("DRIFT  {}", deviation);
+    }
+    Err(format!("{} settings differ from {}", deviations.len(), path).into())
+}
+
+fn compare(set: DataSet, expected: &Startup, actual: &Startup) -> Vec<String> {
+    let mut deviations = Vec::new();
+
+    if expected.stream != actual.stream {
+        deviations.push(format!(
+            "{} stream is {}, expected {}",
+            set.name,
+            enabled(actual.stream),
+            enabled(expected.stream)
+        ));
+    }
+
+    for (descriptor, decimation) in &expected.format {
+        match actual.format.iter().find(|(d, _)| d == descriptor) {
+            Some((_, actual)) if actual == decimation => (),
+            Some((_, actual)) => deviations.push(format!(
+                "{} field 0x{:02X} decimation is {}, expected {}",
+                set.name, descriptor, actual, decimation
+            )),
+            None => deviations.push(format!(
+                "{} field 0x{:02X} is missing",
+                set.name, descriptor
+            )),
+        }
+    }
+
+    for (descriptor, _) in &actual.format {
+        if !expected.format.iter().any(|(d, _)| d == descriptor) {
+            deviations.push(format!(
+                "{} field 0x{:02X} is enabled but not in the baseline",
+                set.name, descriptor
+            ));
+        }
+    }
+
+    deviations
+}
+
+fn enabled(enabled: bool) -> &'static str {
+    if enabled {
+        "enabled"
+    } else {
+        "disabled"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_reports_every_difference() {
+        let expected = Startup {
+            stream: true,
+            format: vec![(0x04, 10), (0x05, 10), (0x07, 10)],
+        };
+        let actual = Startup {
+            stream: false,
+            format: vec![(0x04, 10), (0x05, 20), (0x12, 10)],
+        };
+
+        assert_eq!(
+            compare(device::IMU, &expected, &actual),
+            vec![
+                "IMU stream is disabled, expected enabled",
+                "IMU field 0x05 decimation is 20, expected 10",
+                "IMU field 0x07 is missing",
+                "IMU field 0x12 is enabled but not in the baseline",
+            ]
+        );
+        assert!(compare(device::IMU, &expected, &expected).is_empty());
+    }
+
+    #[test]
+    fn baselines_take_hex_descriptors() {
+        let baseline: Baseline = toml::from_str(
+            "model = \"3DM-GX5-45\"\n[imu]\nstream = true\nformat = [[0x04, 10], [0x05, 10]]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            baseline.get(device::IMU).unwrap().format,
+            vec![(0x04, 10), (0x05, 10)]
+        );
+        assert!(baseline.get(device::GNSS).is_none());
+    }
+}
@@ -0,0 +1,361 @@
+//! Long unattended runs for qualifying a unit and its cabling before it's deployed. The
+//! data sets the device is already set up to stream are watched for rate drops, dropouts
+//! and the filter losing its solution, with a snapshot of the rates and health written to
+//! the report every interval.
+
+use std::{
+    collections::HashMap,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use clap::ArgMatches;
+use lordserial::parser::Lord;
+
+use crate::{
+    capture,
+    channels::Decoder,
+    config::Config,
+    device::{self, DataSet},
+    mip::Frame,
+    partial::{self, PartialFile},
+    time::{self, TimeFormatter},
+    Error,
+};
+
+// filter.state values once the filter has initialized
+const FILTER_RUNNING_VALID: f64 = 2.0;
+const FILTER_RUNNING_ERROR: f64 = 3.0;
+
+// Health channels included in every snapshot when they're being streamed
+const HEALTH_CHANNELS: &[&str] = &[
+    "filter.state",
+    "filter.status_flags",
+    "gnss.fix_type",
+    "gnss.num_sv",
+];
+
+struct Report {
+    file: BufWriter<PartialFile>,
+    times: TimeFormatter,
+    failures: u64,
+}
+
+impl Report {
+    /// Written straight through, so a report from a run that dies is still readable.
+    fn line(&mut self, text: &str) -> Result<(), Error> {
+        let line = format!("{} {}", self.times.format(capture::timestamp()), text);
+        println!("{}", line);
+        writeln!(self.file, "{}", line)?;
+        self.file.flush()?;
+        self.file.get_mut().sync()?;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<PathBuf, Error> {
+        self.file.into_inner()?.commit()
+    }
+
+    fn fail(&mut self, text: &str) -> Result<(), Error> {
+        self.failures += 1;
+        self.line(&format!("FAIL {}", text))
+    }
+}
+
+/// `reopen` opens the port again, for when the device drops off the bus and comes back.
+pub fn run(
+    mut lord: Lord,
+    reopen: &mut dyn FnMut() -> Result<Lord, Error>,
+    matches: &ArgMatches,
+    config: &Config,
+    times: &TimeFormatter,
+) -> Result<(), Error> {
+    let hours = matches.value_of("hours").unwrap();
+    let duration = hours
+        .parse::<f64>()
+        .ok()
+        .and_then(|h| Duration::try_from_secs_f64(h * 3600.0).ok())
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| format!("Invalid number of hours {}", hours))?;
+    let interval = time::parse_duration(matches.value_of("snapshot-interval").unwrap())?;
+    let stall = time::parse_duration(matches.value_of("stall-timeout").unwrap())?;
+    let tolerance: f64 = matches.value_of("rate-tolerance").unwrap().parse()?;
+    let path = matches.value_of("report").unwrap();
+
+    let info = device::device_info(&mut lord)?;
+    let expected = expected_rates(&mut lord)?;
+    if expected.is_empty() {
+        return Err("Nothing is streaming, set up the data sets to soak first".into());
+    }
+
+    let mut report = Report {
+        file: BufWriter::new(PartialFile::create(path)?),
+        times: *times,
+        failures: 0,
+    };
+    report.line(&format!(
+        "soak start model={} serial={} firmware={} hours={} snapshot-interval={}s",
+        info.model_name,
+        info.serial_number,
+        info.firmware_version(),
+        hours,
+        interval.as_secs_f64()
+    ))?;
+    let rates: Vec<String> = expected
+        .iter()
+        .map(|(set, rate)| format!("{}={:.1}Hz", set.name.to_ascii_lowercase(), rate))
+        .collect();
+    report.line(&format!("expected {}", rates.join(" ")))?;
+
+    let mut decoder = Decoder::from_matches(matches, config)?;
+    let mut latest: HashMap<String, f64> = HashMap::new();
+    let mut counts: HashMap<u8, u64> = HashMap::new();
+    let mut filter_state: Option<f64> = None;
+    let mut reconnects = 0u64;
+    let mut divergences = 0u64;
+    // Empty while the port is closed for reopening
+    let mut lord = Some(lord);
+
+    let stop = partial::stop_flag()?;
+    let started = Instant::now();
+    let mut last_snapshot = started;
+    let mut last_packet = started;
+    let mut stalled = false;
+    let mut last_reopen = started;
+    let mut reopen_failed = false;
+
+    while started.elapsed() < duration {
+        if stop.load(Ordering::Relaxed) {
+            report.fail(&format!(
+                "stopped early elapsed={}s",
+                started.elapsed().as_secs()
+            ))?;
+            break;
+        }
+
+        if let Some(data) = lord.as_mut().and_then(Lord::get_data) {
+            // Data coming back after a stall means the device or its link went away for a bit
+            if stalled {
+                stalled = false;
+                reconnects += 1;
+                let gap = last_packet.elapsed().as_secs_f64();
+                report.fail(&format!("reconnect gap={:.1}s", gap))?;
+            }
+            last_packet = Instant::now();
+
+            *counts.entry(data.header.descriptor).or_insert(0) += 1;
+            let decoded = decoder.decode(&Frame::from_packet(&data)?)?;
+            for unknown in decoded.unknown.iter().filter(|u| decoder.is_first(u)) {
+                report.line(&unknown.to_string())?;
+            }
+            latest.extend(decoded.values);
+
+            if let Some(state) = latest.get("filter.state").copied() {
+                if let Some(previous) = filter_state.filter(|p| *p != state) {
+                    report.line(&format!("filter state={} previous={}", state, previous))?;
+
+                    // Losing the solution, or falling back to initializing once running
+                    let running = |s: f64| s >= FILTER_RUNNING_VALID;
+                    if state == FILTER_RUNNING_ERROR || (running(previous) && !running(state)) {
+                        divergences += 1;
+                        report.fail(&format!("filter-divergence state={}", state))?;
+                    }
+                }
+                filter_state = Some(state);
+            }
+        }
+
+        if !stalled && last_packet.elapsed() > stall {
+            stalled = true;
+            last_reopen = Instant::now();
+            reopen_failed = false;
+            report.line(&format!("stall timeout={}s", stall.as_secs_f64()))?;
+        }
+
+        // A device that dropped off the bus only comes back on a fresh handle
+        if stalled && last_reopen.elapsed() > stall {
+            last_reopen = Instant::now();
+            match reconnect(&mut lord, reopen) {
+                Ok(()) => report.line("reopened port")?,
+                // Usually just not back yet, once per stall is enough
+                Err(e) if !reopen_failed => {
+                    reopen_failed = true;
+                    report.line(&format!("reopen failed error={}", e))?;
+                }
+                Err(_) => (),
+            }
+        }
+
+        let elapsed = last_snapshot.elapsed();
+        if elapsed >= interval {
+            last_snapshot = Instant::now();
+            snapshot(
+                &mut report,
+                &expected,
+                &counts,
+                elapsed,
+                Some(tolerance),
+                &latest,
+                started.elapsed(),
+            )?;
+            report.line(&format!(
+                "health reconnects={} divergences={} failures={}",
+                reconnects, divergences, report.failures
+            ))?;
+            counts.clear();
+        }
+    }
+
+    // Whatever came in since the last snapshot, the rates are only checked if it's long
+    // enough that a packet or two either way doesn't read as a drop
+    let elapsed = last_snapshot.elapsed();
+    let check = Some(tolerance).filter(|_| elapsed >= interval / 2);
+    snapshot(
+        &mut report,
+        &expected,
+        &counts,
+        elapsed,
+        check,
+        &latest,
+        started.elapsed(),
+    )?;
+
+    if stalled {
+        report.fail("stalled at the end of the run")?;
+    }
+
+    let result = if report.failures == 0 { "PASS" } else { "FAIL" };
+    report.line(&format!(
+        "soak end result={} failures={} reconnects={} divergences={}",
+        result, report.failures, reconnects, divergences
+    ))?;
+
+    let failures = report.failures;
+    let path = report.finish()?;
+    if failures > 0 {
+        return Err(format!(
+            "Soak failed with {} failures, see {}",
+            failures,
+            path.display()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Replace `handle` with a fresh one from `reopen`, leaving it empty if that fails. The old
+/// handle is closed first: the port is opened exclusively, and a device that re-enumerated
+/// only gets the same node back once nothing holds the old one.
+fn reconnect<T>(
+    handle: &mut Option<T>,
+    reopen: &mut dyn FnMut() -> Result<T, Error>,
+) -> Result<(), Error> {
+    drop(handle.take());
+    *handle = Some(reopen()?);
+    Ok(())
+}
+
+/// Packet rate of every data set that's streaming, from its base rate and the decimation
+/// of its fastest field.
+fn expected_rates(lord: &mut Lord) -> Result<Vec<(DataSet, f64)>, Error> {
+    let mut rates = Vec::new();
+
+    for set in device::DATA_SETS.iter() {
+        // Not every model has every data set
+        if !device::stream_enabled(lord, *set).unwrap_or(false) {
+            continue;
+        }
+
+        let format = device::read_format(lord, *set)?;
+        if let Some(decimation) = format.iter().map(|(_, d)| *d).min() {
+            let base_rate = device::base_rate(lord, *set)?;
+            rates.push((*set, base_rate as f64 / decimation.max(1) as f64));
+        }
+    }
+
+    Ok(rates)
+}
+
+/// Rates are only checked against `tolerance` when there is one.
+fn snapshot(
+    report: &mut Report,
+    expected: &[(DataSet, f64)],
+    counts: &HashMap<u8, u64>,
+    elapsed: Duration,
+    tolerance: Option<f64>,
+    latest: &HashMap<String, f64>,
+    uptime: Duration,
+) -> Result<(), Error> {
+    let mut line = format!("snapshot elapsed={}s", uptime.as_secs());
+    let mut drops = Vec::new();
+
+    for (set, rate) in expected {
+        let count = counts.get(&set.descriptor).copied().unwrap_or(0);
+        let measured = count as f64 / elapsed.as_secs_f64();
+        let name = set.name.to_ascii_lowercase();
+        line.push_str(&format!(" {}={:.1}Hz", name, measured));
+
+        if tolerance.is_some_and(|t| measured < rate * (1.0 - t / 100.0)) {
+            drops.push(format!(
+                "rate-drop set={} rate={:.1}Hz expected={:.1}Hz",
+                name, measured, rate
+            ));
+        }
+    }
+
+    for channel in HEALTH_CHANNELS {
+        if let Some(value) = latest.get(*channel) {
+            line.push_str(&format!(" {}={}", channel, value));
+        }
+    }
+
+    report.line(&line)?;
+    for drop in drops {
+        report.fail(&drop)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "virtual"))]
+mod tests {
+    use std::sync::Arc;
+
+    use serialport::SerialPort;
+
+    use super::*;
+    use crate::{
+        clock::VirtualClock,
+        virtual_port::{ScriptedDevice, VirtualPort},
+    };
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn reconnect_closes_the_old_port_first() {
+        let clock = VirtualClock::new();
+        let port = VirtualPort::scripted(ScriptedDevice::demo(), Arc::new(clock.clone()));
+        let link = port.handle();
+        let mut handle: Option<Box<dyn SerialPort>> = Some(Box::new(port));
+        let mut reopen = || -> Result<Box<dyn SerialPort>, Error> {
+            let mut port = link.open(Arc::new(clock.clone()))?;
+            port.set_timeout(Duration::from_millis(10))?;
+            Ok(Box::new(port))
+        };
+
+        // Nothing to open while the device is off the bus
+        link.disconnect();
+        assert!(reconnect(&mut handle, &mut reopen).is_err());
+        assert!(handle.is_none());
+
+        link.reconnect();
+        reconnect(&mut handle, &mut reopen).unwrap();
+        let port = handle.as_mut().unwrap();
+        assert!(device::query_info(&mut **port, &clock, TIMEOUT).is_ok());
+
+        // And again while the last reopen still has it
+        reconnect(&mut handle, &mut reopen).unwrap();
+        assert!(handle.is_some());
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+
+use clap::ArgMatches;
+
+use crate::{
+    capture::{CaptureReader, Kind},
+    channels::Decoder,
+    config::Config,
+    device,
+    mip::Frame,
+    Error,
+};
+
+pub fn run(matches: &ArgMatches, config: &Config) -> Result<(), Error> {
+    let input = matches.value_of("INPUT").unwrap();
+    let mut reader = CaptureReader::open(input)?;
+    let mut decoder = Decoder::from_matches(matches, config)?;
+
+    let mut packets: BTreeMap<u8, u64> = BTreeMap::new();
+    let mut events = 0u64;
+    let mut malformed = 0u64;
+    let mut samples = 0u64;
+    let mut span: Option<(u64, u64)> = None;
+
+    while let Some(record) = reader.next_record()? {
+        span = Some(match span {
+            Some((first, last)) => (first.min(record.time), last.max(record.time)),
+            None => (record.time, record.time),
+        });
+
+        if record.kind == Kind::Event {
+            events += 1;
+            continue;
+        }
+
+        match Frame::parse(&record.data) {
+            Some(frame) => {
+                *packets.entry(frame.descriptor).or_insert(0) += 1;
+                samples += decoder.decode(&frame)?.values.len() as u64;
+            }
+            None => malformed += 1,
+        }
+    }
+
+    let seconds = span.map_or(0.0, |(first, last)| (last - first) as f64 / 1e9);
+    println!("Capture    {}", input);
+    println!("Duration   {:.1}s", seconds);
+    println!("Events     {}", events);
+    println!("Samples    {}", samples);
+    println!("Malformed  {}", malformed);
+    if !reader.is_complete() {
+        println!("Footer     missing, the file may be cut short (see lordcli recover)");
+    }
+    println!();
+    println!("{:<14} {:>10} {:>10}", "Data set", "Packets", "Rate");
+
+    for (descriptor, count) in &packets {
+        let name = device::DATA_SETS
+            .iter()
+            .find(|s| s.descriptor == *descriptor)
+            .map_or("", |s| s.name);
+        let rate = if seconds > 0.0 {
+            *count as f64 / seconds
+        } else {
+            0.0
+        };
+
+        println!(
+            "0x{:02X} {:<9} {:>10} {:>7.1} Hz",
+            descriptor, name, count, rate
+        );
+    }
+
+    let unknown = decoder.unknown_counts();
+    if !unknown.is_empty() {
+        println!();
+        println!("{:<14} {:>10}", "Unknown field", "Count");
+        for ((set, field), count) in unknown {
+            println!("0x{:02X}/0x{:02X}      {:>10}", set, field, count);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use std::{str::FromStr, time::Duration};
+
+use chrono::{DateTime, Local, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use clap::ArgMatches;
+
+use crate::Error;
+
+pub const TIME_FORMATS: &[&str] = &["iso8601", "unix", "gps", "tow-week"];
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// 1980-01-06T00:00:00Z
+const GPS_EPOCH: u64 = 315_964_800;
+// GPS time has been ahead of UTC by 18 seconds since the start of 2017
+const LEAP_SECONDS: u64 = 18;
+const SECONDS_PER_WEEK: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeFormat {
+    Iso8601,
+    Unix,
+    Gps,
+    TowWeek,
+}
+
+impl FromStr for TimeFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iso8601" => Ok(TimeFormat::Iso8601),
+            "unix" => Ok(TimeFormat::Unix),
+            "gps" => Ok(TimeFormat::Gps),
+            "tow-week" => Ok(TimeFormat::TowWeek),
+            _ => Err(format!("Unknown time format {}", s).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Zone {
+    Utc,
+    Local,
+    Named(Tz),
+}
+
+impl FromStr for Zone {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "UTC" | "utc" | "Z" => Ok(Zone::Utc),
+            "local" => Ok(Zone::Local),
+            _ => Tz::from_str(s)
+                .map(Zone::Named)
+                .map_err(|_| format!("Unknown timezone {}", s).into()),
+        }
+    }
+}
+
+/// Renders host timestamps (nanoseconds since the unix epoch) the same way for every output.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeFormatter {
+    format: TimeFormat,
+    // Only affects iso8601, the other formats are counts from a fixed epoch
+    zone: Zone,
+}
+
+impl TimeFormatter {
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self, Error> {
+        Ok(TimeFormatter {
+            format: matches.value_of("time-format").unwrap().parse()?,
+            zone: matches.value_of("timezone").unwrap().parse()?,
+        })
+    }
+
+    pub fn format(&self, nanos: u64) -> String {
+        let secs = nanos / NANOS_PER_SEC;
+        let millis = nanos % NANOS_PER_SEC / 1_000_000;
+
+        match self.format {
+            TimeFormat::Iso8601 => {
+                // u64 nanoseconds only reach 2554, well within what chrono can represent
+                let utc = Utc
+                    .timestamp_opt(secs as i64, (nanos % NANOS_PER_SEC) as u32)
+                    .unwrap();
+                match self.zone {
+                    Zone::Utc => rfc3339(utc),
+                    Zone::Local => rfc3339(utc.with_timezone(&Local)),
+                    Zone::Named(tz) => rfc3339(utc.with_timezone(&tz)),
+                }
+            }
+            TimeFormat::Unix => format!("{}.{:03}", secs, millis),
+            TimeFormat::Gps => format!("{}.{:03}", gps_seconds(secs), millis),
+            TimeFormat::TowWeek => {
+                let gps = gps_seconds(secs);
+                format!(
+                    "{}.{:03}:{}",
+                    gps % SECONDS_PER_WEEK,
+                    millis,
+                    gps / SECONDS_PER_WEEK
+                )
+            }
+        }
+    }
+}
+
+fn rfc3339<Z: TimeZone>(time: DateTime<Z>) -> String
+where
+    Z::Offset: std::fmt::Display,
+{
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// GPS time of week in seconds for a host timestamp.
+pub fn time_of_week(nanos: u64) -> f64 {
+    let gps = gps_seconds(nanos / NANOS_PER_SEC);
+    (gps % SECONDS_PER_WEEK) as f64 + (nanos % NANOS_PER_SEC) as f64 / NANOS_PER_SEC as f64
+}
+
+fn gps_seconds(unix: u64) -> u64 {
+    (unix + LEAP_SECONDS).saturating_sub(GPS_EPOCH)
+}
+
+/// Parse durations like `90s`, `5min`, `1h30m` or `250ms`. A bare number is seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration, Error> {
+    let invalid = || format!("Invalid duration {}", duration);
+    let mut rest = duration.trim();
+    if rest.is_empty() {
+        return Err(invalid().into());
+    }
+
+    let mut total = Duration::from_secs(0);
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(split);
+        let number: f64 = number.parse().map_err(|_| invalid())?;
+
+        let split = tail
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(split);
+        let seconds = match unit {
+            "" | "s" | "sec" => 1.0,
+            "ms" => 0.001,
+            "m" | "min" => 60.0,
+            "h" | "hr" => 3600.0,
+            "d" => 86400.0,
+            _ => return Err(format!("Unknown unit {} in duration {}", unit, duration).into()),
+        };
+
+        total += Duration::from_secs_f64(number * seconds);
+        rest = tail.trim_start();
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2021-03-04T05:06:07.890Z
+    const NANOS: u64 = 1_614_834_367_890_000_000;
+
+    fn formatter(format: TimeFormat) -> TimeFormatter {
+        TimeFormatter {
+            format,
+            zone: Zone::Utc,
+        }
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            formatter(TimeFormat::Iso8601).format(NANOS),
+            "2021-03-04T05:06:07.890Z"
+        );
+        assert_eq!(formatter(TimeFormat::Unix).format(NANOS), "1614834367.890");
+        assert_eq!(formatter(TimeFormat::Gps).format(NANOS), "1298869585.890");
+        assert_eq!(
+            formatter(TimeFormat::TowWeek).format(NANOS),
+            "363985.890:2147"
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse_duration("5 parsecs").is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::{
+    clock::Clock,
+    mip::{PacketStarts, Scanner},
+    Error,
+};
+
+#[cfg(feature = "virtual")]
+use crate::virtual_port::{self, ScriptedDevice, VirtualPort};
+
+pub const BAUD_RATE: u32 = 115200;
+
+// A line this quiet has nothing half sent on it
+const QUIET: Duration = Duration::from_millis(50);
+// Longest to look for a frame boundary on a device that's already streaming
+const RESYNC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Open `port_name`, `clock` drives the virtual port when that's what's asked for.
+pub fn open(
+    port_name: &str,
+    #[cfg_attr(not(feature = "virtual"), allow(unused_variables))] clock: Arc<dyn Clock>,
+) -> Result<Box<dyn SerialPort>, Error> {
+    #[cfg(feature = "virtual")]
+    {
+        if port_name == virtual_port::PORT_NAME {
+            return Ok(Box::new(VirtualPort::scripted(
+                ScriptedDevice::demo(),
+                clock,
+            )));
+        }
+    }
+
+    Ok(serialport::new(port_name, BAUD_RATE).open()?)
+}
+
+/// Throw away whatever was buffered before the port was opened and line up with the start
+/// of the next packet, so the first reply isn't read from behind half a stale data packet.
+/// Returns the number of bytes discarded.
+pub fn resync(port: &mut dyn SerialPort, clock: &dyn Clock) -> Result<usize, Error> {
+    let timeout = port.timeout();
+    port.clear(ClearBuffer::All)?;
+    port.set_timeout(QUIET)?;
+    let discarded = read_to_boundary(port, clock);
+    port.set_timeout(timeout)?;
+    discarded
+}
+
+fn read_to_boundary(port: &mut dyn SerialPort, clock: &dyn Clock) -> Result<usize, Error> {
+    let started = clock.now();
+    let mut scanner = Scanner::new();
+    let mut read = 0;
+    let mut byte = [0u8];
+
+    // A byte at a time so nothing past the end of a frame is taken from the parser
+    loop {
+        match port.read(&mut byte) {
+            Ok(0) => return Ok(read),
+            Ok(_) => {
+                read += 1;
+                scanner.push(&byte);
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(read),
+            Err(e) => return Err(e.into()),
+        }
+
+        if scanner.next_frame().is_some() {
+            return Ok(read);
+        }
+
+        if clock.now() - started > RESYNC_TIMEOUT {
+            return Err(format!(
+                "No valid packets in {} bytes ({} pending), is the baud rate {}?",
+                scanner.discarded(),
+                scanner.pending(),
+                BAUD_RATE
+            )
+            .into());
+        }
+    }
+}
+
+/// Limits on how fast commands are written, for adapters and older firmware that drop
+/// bytes when large commands arrive back to back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pacing {
+    /// Minimum gap between the end of one packet and the start of the next
+    pub command_delay: Duration,
+    /// Largest number of bytes written at once, 0 for no limit
+    pub write_chunk: usize,
+    /// Gap between the chunks of a single write
+    pub chunk_delay: Duration,
+}
+
+impl Pacing {
+    pub fn is_none(&self) -> bool {
+        *self == Pacing::default()
+    }
+}
+
+/// Wrap `port` so every write follows `pacing`, timed by `clock`. Packets are found in
+/// what's written, so it doesn't matter how the writer splits them up.
+pub fn pace(
+    port: Box<dyn SerialPort>,
+    pacing: Pacing,
+    clock: Arc<dyn Clock>,
+) -> Box<dyn SerialPort> {
+    if pacing.is_none() {
+        return port;
+    }
+
+    Box::new(PacedPort {
+        inner: port,
+        pacing,
+        clock,
+        written: Arc::new(Mutex::new(Written::default())),
+    })
+}
+
+struct PacedPort {
+    inner: Box<dyn SerialPort>,
+    pacing: Pacing,
+    clock: Arc<dyn Clock>,
+    // Shared between clones so the gap holds whichever handle does the writing
+    written: Arc<Mutex<Written>>,
+}
+
+#[derive(Default)]
+struct Written {
+    starts: PacketStarts,
+    last_write: Option<Duration>,
+}
+
+impl Read for PacedPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for PacedPort {
+    /// Writes all of `buf` before returning, waiting out the command delay before each
+    /// packet that starts in it.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = self.written.lock().unwrap();
+        let starts = written.starts.push(buf);
+
+        let mut pieces = starts.clone();
+        if pieces.first() != Some(&0) {
+            pieces.insert(0, 0);
+        }
+        pieces.push(buf.len());
+
+        for bounds in pieces.windows(2) {
+            let piece = &buf[bounds[0]..bounds[1]];
+            if piece.is_empty() {
+                continue;
+            }
+
+            if starts.contains(&bounds[0]) {
+                if let Some(last) = written.last_write {
+                    let since = self.clock.now() - last;
+                    if since < self.pacing.command_delay {
+                        self.clock.sleep(self.pacing.command_delay - since);
+                    }
+                }
+            }
+
+            let chunk = match self.pacing.write_chunk {
+                0 => piece.len(),
+                size => size,
+            };
+            for (i, part) in piece.chunks(chunk).enumerate() {
+                if i > 0 {
+                    self.clock.sleep(self.pacing.chunk_delay);
+                }
+                self.inner.write_all(part)?;
+                self.inner.flush()?;
+            }
+
+            written.last_write = Some(self.clock.now());
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SerialPort for PacedPort {
+    fn name(&self) -> Option<String> {
+        self.inner.name()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.inner.baud_rate()
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        self.inner.data_bits()
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        self.inner.flow_control()
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        self.inner.parity()
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        self.inner.stop_bits()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        self.inner.set_data_bits(data_bits)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.inner.set_flow_control(flow_control)
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        self.inner.set_parity(parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        self.inner.set_stop_bits(stop_bits)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_request_to_send(level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_data_terminal_ready(level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.inner.read_clear_to_send()
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.inner.read_data_set_ready()
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.inner.read_ring_indicator()
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.inner.read_carrier_detect()
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_read()
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_write()
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        self.inner.clear(buffer_to_clear)
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(PacedPort {
+            inner: self.inner.try_clone()?,
+            pacing: self.pacing,
+            clock: self.clock.clone(),
+            written: self.written.clone(),
+        }))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        self.inner.set_break()
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.inner.clear_break()
+    }
+}
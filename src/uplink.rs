@@ -0,0 +1,282 @@
+//! Low rate health and position summaries posted over HTTP, for remote stations where the
+//! only link is metered cellular. `uplink` does nothing but post them, `record --uplink`
+//! posts them alongside the full rate recording.
+//!
+//! Posting happens on its own thread so a slow or dead link never holds up reading the
+//! device. A summary that's ready while the last one is still being sent is dropped rather
+//! than queued, the next one supersedes it anyway.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, SyncSender, TrySendError},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ArgMatches;
+use lordserial::parser::Lord;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    capture,
+    channels::Decoder,
+    config::Config,
+    device::{self, DeviceInfo},
+    mip::{self, Command, Frame},
+    partial, time, Error,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+// Summaries waiting for the poster thread, beyond this they're dropped
+const QUEUE_LEN: usize = 1;
+
+/// What starting an uplink sends, it only asks the device what it is.
+pub const COMMANDS: &[Command] = &[mip::DEVICE_INFO];
+
+// Channels included in the summary when they're being streamed, under a shorter name
+const SUMMARY_CHANNELS: &[(&str, &str)] = &[
+    ("filter.lat", "lat"),
+    ("filter.lon", "lon"),
+    ("filter.alt", "alt"),
+    ("filter.state", "filter_state"),
+    ("filter.status_flags", "filter_flags"),
+    ("gnss.fix_type", "fix_type"),
+    ("gnss.num_sv", "num_sv"),
+    ("gnss.h_acc", "h_acc"),
+    ("imu.pressure", "pressure"),
+];
+
+pub struct Uplink {
+    summaries: SyncSender<Value>,
+    interval: Duration,
+    info: DeviceInfo,
+    derived: Vec<String>,
+    started: Instant,
+    last_post: Instant,
+    latest: HashMap<String, f64>,
+    counts: HashMap<u8, u64>,
+}
+
+impl Uplink {
+    /// Start posting to `record --uplink`, if it was given. The `derived` channels are
+    /// included in the summaries under their own names.
+    pub fn from_matches(
+        lord: &mut Lord,
+        matches: &ArgMatches,
+        derived: Vec<String>,
+    ) -> Result<Option<Self>, Error> {
+        let url = match matches.value_of("uplink") {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let interval = time::parse_duration(matches.value_of("uplink-interval").unwrap())?;
+
+        Ok(Some(Uplink::new(
+            device::device_info(lord)?,
+            url,
+            interval,
+            derived,
+        )))
+    }
+
+    /// Start the poster thread for summaries about the device described by `info`.
+    pub fn new(info: DeviceInfo, url: &str, interval: Duration, derived: Vec<String>) -> Self {
+        println!(
+            "Posting summaries to {} every {}s",
+            url,
+            interval.as_secs_f64()
+        );
+
+        let (summaries, queue) = mpsc::sync_channel::<Value>(QUEUE_LEN);
+        let url = url.to_string();
+        thread::spawn(move || {
+            for summary in queue {
+                match post(&url, &summary) {
+                    Ok(()) => println!("Posted summary: {}", summary),
+                    Err(e) => eprintln!("Failed to post summary: {}", e),
+                }
+            }
+        });
+
+        Uplink {
+            summaries,
+            interval,
+            info,
+            derived,
+            started: Instant::now(),
+            last_post: Instant::now(),
+            latest: HashMap::new(),
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Take in the decoded channels of one packet from data set `set`.
+    pub fn add(&mut self, set: u8, values: &[(String, f64)]) {
+        *self.counts.entry(set).or_insert(0) += 1;
+        for (name, value) in values {
+            self.latest.insert(name.clone(), *value);
+        }
+    }
+
+    /// Hand a summary to the poster thread once the interval is up, whether or not any
+    /// data has come in.
+    pub fn tick(&mut self) {
+        let elapsed = self.last_post.elapsed();
+        if elapsed < self.interval {
+            return;
+        }
+        self.last_post = Instant::now();
+
+        let summary = self.summary(elapsed);
+        match self.summaries.try_send(summary) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                eprintln!("Still posting the last summary, dropped this one")
+            }
+            Err(TrySendError::Disconnected(_)) => eprintln!("Uplink thread has stopped"),
+        }
+    }
+
+    fn summary(&mut self, elapsed: Duration) -> Value {
+        let mut rates = Map::new();
+        for set in device::DATA_SETS.iter() {
+            let count = self.counts.get(&set.descriptor).copied().unwrap_or(0);
+            let rate = (count as f64 / elapsed.as_secs_f64() * 10.0).round() / 10.0;
+            rates.insert(set.name.to_ascii_lowercase(), json!(rate));
+        }
+        self.counts.clear();
+
+        let mut summary = json!({
+            "time": capture::timestamp() / 1_000_000_000,
+            "serial": self.info.serial_number,
+            "model": self.info.model_name,
+            "uptime": self.started.elapsed().as_secs(),
+            "rates": rates,
+        });
+        for (channel, key) in SUMMARY_CHANNELS {
+            if let Some(value) = self.latest.get(*channel) {
+                summary[*key] = json!(value);
+            }
+        }
+
+        let derived: Map<String, Value> = self
+            .derived
+            .iter()
+            .filter_map(|name| Some((name.clone(), json!(self.latest.get(name)?))))
+            .collect();
+        if !derived.is_empty() {
+            summary["derived"] = Value::Object(derived);
+        }
+        summary
+    }
+}
+
+/// Stream and post summaries to `--http` until stopped, without recording anything.
+pub fn run(lord: &mut Lord, matches: &ArgMatches, config: &Config) -> Result<(), Error> {
+    let mut decoder = Decoder::from_matches(matches, config)?;
+    let interval = time::parse_duration(matches.value_of("interval").unwrap())?;
+    let mut uplink = Uplink::new(
+        device::device_info(lord)?,
+        matches.value_of("http").unwrap(),
+        interval,
+        decoder.derived_channels(),
+    );
+
+    let stop = partial::stop_flag()?;
+    while !stop.load(Ordering::Relaxed) {
+        if let Some(data) = lord.get_data() {
+            let frame = Frame::from_packet(&data)?;
+            let decoded = decoder.decode(&frame)?;
+            for unknown in decoded.unknown.iter().filter(|u| decoder.is_first(u)) {
+                eprintln!("{}", unknown);
+            }
+            uplink.add(frame.descriptor, &decoded.values);
+        }
+        uplink.tick();
+    }
+
+    Ok(())
+}
+
+fn post(url: &str, summary: &Value) -> Result<(), Error> {
+    ureq::post(url)
+        .timeout(REQUEST_TIMEOUT)
+        .send_json(summary.clone())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// Body of the next request made to `listener`, answering it with `status`.
+    fn receive(listener: &TcpListener, status: Option<&str>) -> Value {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_ascii_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        if let Some(status) = status {
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            let mut stream: TcpStream = reader.into_inner();
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn summaries_are_posted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/telemetry", listener.local_addr().unwrap());
+        let info = DeviceInfo {
+            firmware: 1108,
+            model_name: "3DM-GX5-45".to_string(),
+            model_number: "6251-4220".to_string(),
+            serial_number: "12345".to_string(),
+            options: String::new(),
+        };
+        let mut uplink = Uplink::new(info, &url, Duration::from_secs(0), vec!["speed".into()]);
+
+        uplink.add(
+            mip::FILTER_DATA,
+            &[("filter.lat".to_string(), 40.5), ("speed".to_string(), 2.5)],
+        );
+        uplink.tick();
+        // A link that drops the connection doesn't stop the next summary
+        let summary = receive(&listener, None);
+        assert_eq!(summary["serial"], "12345");
+        assert_eq!(summary["model"], "3DM-GX5-45");
+        assert_eq!(summary["lat"], 40.5);
+        assert_eq!(summary["derived"]["speed"], 2.5);
+        assert!(summary["rates"]["filter"].as_f64().unwrap() > 0.0);
+
+        uplink.tick();
+        let summary = receive(&listener, Some("200 OK"));
+        assert_eq!(summary["lat"], 40.5);
+        assert_eq!(summary["rates"]["filter"], 0.0);
+    }
+}
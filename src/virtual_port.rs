@@ -0,0 +1,639 @@
+//! In-memory stand-in for a serial port, for exercising command sequencing without hardware.
+//!
+//! The port is attached to a [`ScriptedDevice`] that answers commands and streams data.
+//! Everything is driven by a [`Clock`], so in tests with a virtual clock timeouts and
+//! stream rates are fully deterministic.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::{
+    clock::Clock,
+    mip::{self, Command, Frame, RawField, Scanner},
+};
+
+pub const PORT_NAME: &str = "virtual";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone)]
+pub enum Reply {
+    /// Ack (or nack, if non-zero) with the given error code.
+    Ack(u8),
+    /// Successful ack followed by response fields.
+    Data(Vec<RawField>),
+    /// Swallow the command without answering.
+    #[cfg_attr(not(test), allow(dead_code))]
+    Silent,
+    /// Arbitrary bytes, for garbage or truncated packets.
+    #[cfg_attr(not(test), allow(dead_code))]
+    Raw(Vec<u8>),
+}
+
+struct Stream {
+    bytes: Vec<u8>,
+    period: Duration,
+    next: Duration,
+}
+
+#[derive(Default)]
+pub struct ScriptedDevice {
+    scanner: Scanner,
+    defaults: HashMap<Command, (Duration, Reply)>,
+    queued: HashMap<Command, VecDeque<(Duration, Reply)>>,
+    streams: Vec<Stream>,
+    idle: bool,
+    pending: Vec<(Duration, Vec<u8>)>,
+    received: Vec<Frame>,
+}
+
+impl ScriptedDevice {
+    pub fn new() -> Self {
+        ScriptedDevice::default()
+    }
+
+    /// IMU only device streaming at 100Hz, enough to poke at the CLI with.
+    pub fn demo() -> Self {
+        let text = |s: &str| format!("{:<16}", s).into_bytes();
+        let mut info = 1108u16.to_be_bytes().to_vec();
+        for s in &["3DM-CV5-10", "6294-4220", "VIRTUAL", "0", "8g,300dps"] {
+            info.extend(text(s));
+        }
+
+        let vector = |x: f32, y: f32, z: f32| {
+            [x, y, z]
+                .iter()
+                .flat_map(|v| v.to_be_bytes().to_vec())
+                .collect::<Vec<u8>>()
+        };
+
+        ScriptedDevice::new()
+            .reply(
+                mip::DEVICE_INFO,
+                Reply::Data(vec![RawField::new(0x81, info)]),
+            )
+            .reply(
+                mip::IMU_BASE_RATE,
+                Reply::Data(vec![RawField::new(0x83, 1000u16.to_be_bytes().to_vec())]),
+            )
+            .stream(
+                Frame::new(
+                    mip::IMU_DATA,
+                    vec![
+                        RawField::new(0x04, vector(0.0, 0.0, -1.0)),
+                        RawField::new(0x05, vector(0.0, 0.0, 0.0)),
+                    ],
+                ),
+                Duration::from_millis(10),
+            )
+    }
+
+    /// Answer every `command` with `reply`. Commands without a reply are acked.
+    pub fn reply(mut self, command: Command, reply: Reply) -> Self {
+        self.defaults
+            .insert(command, (Duration::from_secs(0), reply));
+        self
+    }
+
+    /// Answer the next `command` with `reply` after `delay`, ahead of any default reply.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn reply_once(mut self, command: Command, delay: Duration, reply: Reply) -> Self {
+        self.queued
+            .entry(command)
+            .or_default()
+            .push_back((delay, reply));
+        self
+    }
+
+    pub fn stream(mut self, frame: Frame, period: Duration) -> Self {
+        self.streams.push(Stream {
+            bytes: frame.encode().expect("Streamed frame is too long"),
+            period,
+            next: Duration::from_secs(0),
+        });
+        self
+    }
+
+    fn receive(&mut self, bytes: &[u8], now: Duration) {
+        self.scanner.push(bytes);
+
+        while let Some((frame, _)) = self.scanner.next_frame() {
+            for field in &frame.fields {
+                let command = Command {
+                    set: frame.descriptor,
+                    field: field.descriptor,
+                };
+
+                match command {
+                    mip::SET_IDLE => self.idle = true,
+                    mip::RESUME => self.idle = false,
+                    _ => (),
+                }
+
+                let (delay, reply) = self
+                    .queued
+                    .get_mut(&command)
+                    .and_then(|q| q.pop_front())
+                    .or_else(|| self.defaults.get(&command).cloned())
+                    .unwrap_or((Duration::from_secs(0), Reply::Ack(0)));
+
+                let bytes = match reply {
+                    Reply::Ack(code) => Some(ack(command, code, vec![])),
+                    Reply::Data(fields) => Some(ack(command, 0, fields)),
+                    Reply::Silent => None,
+                    Reply::Raw(bytes) => Some(bytes),
+                };
+
+                if let Some(bytes) = bytes {
+                    self.pending.push((now + delay, bytes));
+                }
+            }
+
+            self.received.push(frame);
+        }
+    }
+
+    fn poll(&mut self, now: Duration, out: &mut VecDeque<u8>) {
+        let (due, pending): (Vec<_>, Vec<_>) =
+            self.pending.drain(..).partition(|(at, _)| *at <= now);
+        self.pending = pending;
+        for (_, bytes) in due {
+            out.extend(bytes);
+        }
+
+        if self.idle {
+            return;
+        }
+
+        for stream in &mut self.streams {
+            while stream.next <= now {
+                out.extend(&stream.bytes);
+                stream.next += stream.period;
+            }
+        }
+    }
+}
+
+fn ack(command: Command, code: u8, fields: Vec<RawField>) -> Vec<u8> {
+    let mut all = vec![RawField::new(mip::ACK_FIELD, vec![command.field, code])];
+    all.extend(fields);
+    Frame::new(command.set, all)
+        .encode()
+        .expect("Scripted reply is too long")
+}
+
+struct Link {
+    // Bytes from the device waiting to be read
+    rx: VecDeque<u8>,
+    device: ScriptedDevice,
+    connected: bool,
+    // Ports on the link, clones included, which like a real port keep it from reopening
+    open: usize,
+}
+
+impl Link {
+    fn check(&self) -> io::Result<()> {
+        if self.connected {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Virtual device disconnected",
+            ))
+        }
+    }
+
+    fn poll(&mut self, now: Duration) {
+        self.device.poll(now, &mut self.rx);
+    }
+}
+
+/// Shared handle onto a virtual link, kept by a test after the port itself is handed off.
+#[derive(Clone)]
+pub struct LinkHandle {
+    link: Arc<Mutex<Link>>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl LinkHandle {
+    /// Every read and write fails until [`LinkHandle::reconnect`] is called.
+    pub fn disconnect(&self) {
+        self.link.lock().unwrap().connected = false;
+    }
+
+    /// Anything that was in flight when the link dropped is lost.
+    pub fn reconnect(&self) {
+        let mut link = self.link.lock().unwrap();
+        link.rx.clear();
+        link.connected = true;
+    }
+
+    /// Every command frame the device has seen so far.
+    pub fn received(&self) -> Vec<Frame> {
+        self.link.lock().unwrap().device.received.clone()
+    }
+
+    /// Open the port again. Like a real port this fails while it's disconnected, and while
+    /// any other handle on it is still open.
+    pub fn open(&self, clock: Arc<dyn Clock>) -> io::Result<VirtualPort> {
+        let mut link = self.link.lock().unwrap();
+        if !link.connected {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Virtual device disconnected",
+            ));
+        }
+        if link.open > 0 {
+            return Err(io::Error::other("Virtual port is already open"));
+        }
+
+        link.open += 1;
+        Ok(VirtualPort {
+            link: self.link.clone(),
+            clock,
+            timeout: Duration::from_secs(0),
+            baud_rate: 115200,
+        })
+    }
+}
+
+pub struct VirtualPort {
+    link: Arc<Mutex<Link>>,
+    clock: Arc<dyn Clock>,
+    timeout: Duration,
+    baud_rate: u32,
+}
+
+impl VirtualPort {
+    /// Port with `device` on the other end.
+    pub fn scripted(device: ScriptedDevice, clock: Arc<dyn Clock>) -> VirtualPort {
+        VirtualPort {
+            link: Arc::new(Mutex::new(Link {
+                rx: VecDeque::new(),
+                device,
+                connected: true,
+                open: 1,
+            })),
+            clock,
+            timeout: Duration::from_secs(0),
+            baud_rate: 115200,
+        }
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn handle(&self) -> LinkHandle {
+        LinkHandle {
+            link: self.link.clone(),
+        }
+    }
+
+    fn clone_port(&self) -> VirtualPort {
+        self.link.lock().unwrap().open += 1;
+        VirtualPort {
+            link: self.link.clone(),
+            clock: self.clock.clone(),
+            timeout: self.timeout,
+            baud_rate: self.baud_rate,
+        }
+    }
+}
+
+impl Drop for VirtualPort {
+    fn drop(&mut self) {
+        self.link.lock().unwrap().open -= 1;
+    }
+}
+
+impl Read for VirtualPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.clock.now() + self.timeout;
+
+        loop {
+            {
+                let mut guard = self.link.lock().unwrap();
+                let link = &mut *guard;
+                link.check()?;
+                link.poll(self.clock.now());
+
+                let rx = &mut link.rx;
+                if !rx.is_empty() {
+                    let count = buf.len().min(rx.len());
+                    for (dst, src) in buf.iter_mut().zip(rx.drain(..count)) {
+                        *dst = src;
+                    }
+                    return Ok(count);
+                }
+            }
+
+            let now = self.clock.now();
+            if now >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.clock.sleep((deadline - now).min(POLL_INTERVAL));
+        }
+    }
+}
+
+impl Write for VirtualPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = self.clock.now();
+        let mut guard = self.link.lock().unwrap();
+        let link = &mut *guard;
+        link.check()?;
+        link.device.receive(buf, now);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for VirtualPort {
+    fn name(&self) -> Option<String> {
+        Some(PORT_NAME.to_string())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        let mut link = self.link.lock().unwrap();
+        link.poll(self.clock.now());
+        Ok(link.rx.len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        match buffer_to_clear {
+            ClearBuffer::Input | ClearBuffer::All => self.link.lock().unwrap().rx.clear(),
+            ClearBuffer::Output => (),
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone_port()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::VirtualClock,
+        device,
+        transport::{self, Pacing},
+    };
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn connect(device: ScriptedDevice) -> (VirtualPort, VirtualClock) {
+        let clock = VirtualClock::new();
+        let mut port = VirtualPort::scripted(device, Arc::new(clock.clone()));
+        port.set_timeout(Duration::from_millis(10)).unwrap();
+        (port, clock)
+    }
+
+    fn sent(handle: &LinkHandle) -> Vec<Command> {
+        handle
+            .received()
+            .iter()
+            .flat_map(|f| {
+                f.fields.iter().map(move |field| Command {
+                    set: f.descriptor,
+                    field: field.descriptor,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn commands_are_answered_in_order_while_streaming() {
+        let (mut port, clock) = connect(ScriptedDevice::demo());
+        let handle = port.handle();
+
+        let info = device::query_info(&mut port, &clock, TIMEOUT).unwrap();
+        assert_eq!(info.model_name, "3DM-CV5-10");
+        assert_eq!(info.firmware_version(), "1.1.08");
+
+        let reply = device::send_raw(&mut port, &clock, mip::IMU_BASE_RATE, vec![], TIMEOUT);
+        assert_eq!(
+            device::parse_base_rate(&reply.unwrap(), device::IMU).unwrap(),
+            1000
+        );
+        device::send_raw(&mut port, &clock, mip::SET_IDLE, vec![], TIMEOUT).unwrap();
+
+        assert_eq!(
+            sent(&handle),
+            vec![mip::DEVICE_INFO, mip::IMU_BASE_RATE, mip::SET_IDLE]
+        );
+    }
+
+    #[test]
+    fn nack_is_an_error() {
+        let device = ScriptedDevice::new().reply(mip::IMU_FORMAT, Reply::Ack(0x03));
+        let (mut port, clock) = connect(device);
+
+        let error = device::send_raw(&mut port, &clock, mip::IMU_FORMAT, vec![mip::READ], TIMEOUT)
+            .unwrap_err();
+        assert!(error.to_string().contains("rejected with error 0x03"));
+
+        // Anything without a scripted reply is acked
+        device::send_raw(&mut port, &clock, mip::PING, vec![], TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn silence_times_out_on_the_clock() {
+        let device = ScriptedDevice::new().reply(mip::PING, Reply::Silent);
+        let (mut port, clock) = connect(device);
+
+        let start = clock.now();
+        let error = device::send_raw(&mut port, &clock, mip::PING, vec![], TIMEOUT).unwrap_err();
+        assert!(error.to_string().starts_with("No reply"));
+        assert_eq!(clock.now() - start, TIMEOUT);
+    }
+
+    #[test]
+    fn late_reply_is_missed() {
+        let late = TIMEOUT + Duration::from_millis(50);
+        let device = ScriptedDevice::new().reply_once(mip::PING, late, Reply::Ack(0));
+        let (mut port, clock) = connect(device);
+
+        assert!(device::send_raw(&mut port, &clock, mip::PING, vec![], TIMEOUT).is_err());
+        clock.advance(late);
+        assert!(device::send_raw(&mut port, &clock, mip::PING, vec![], TIMEOUT).is_ok());
+    }
+
+    #[test]
+    fn retry_after_dropped_command() {
+        let device =
+            ScriptedDevice::new().reply_once(mip::PING, Duration::from_secs(0), Reply::Silent);
+        let (mut port, clock) = connect(device);
+        let handle = port.handle();
+
+        assert!(device::send_raw(&mut port, &clock, mip::PING, vec![], TIMEOUT).is_err());
+        assert!(device::send_raw(&mut port, &clock, mip::PING, vec![], TIMEOUT).is_ok());
+        assert_eq!(sent(&handle), vec![mip::PING, mip::PING]);
+    }
+
+    #[test]
+    fn reconnect_after_disconnect() {
+        let (mut port, clock) = connect(ScriptedDevice::demo());
+        let handle = port.handle();
+
+        handle.disconnect();
+        let error = device::send_raw(&mut port, &clock, mip::PING, vec![], TIMEOUT).unwrap_err();
+        assert!(error.to_string().contains("disconnected"));
+        assert!(sent(&handle).is_empty());
+
+        handle.reconnect();
+        assert!(device::query_info(&mut port, &clock, TIMEOUT).is_ok());
+        assert_eq!(sent(&handle), vec![mip::DEVICE_INFO]);
+    }
+
+    #[test]
+    fn resync_lands_on_a_packet_boundary() {
+        // A reply cut short ahead of the next data packet
+        let garbage = vec![mip::SYNC_ONE, mip::SYNC_TWO, mip::IMU_DATA, 0x20, 0x01];
+        let device = ScriptedDevice::demo().reply(mip::PING, Reply::Raw(garbage.clone()));
+        let (mut port, clock) = connect(device);
+        port.write_all(&mip::command(mip::PING, vec![]).encode().unwrap())
+            .unwrap();
+
+        let discarded = transport::resync(&mut port, &clock).unwrap();
+        assert!(discarded > garbage.len());
+
+        let mut buffer = [0u8; 256];
+        let count = port.read(&mut buffer).unwrap();
+        let frame = Frame::parse(&buffer[..count]).unwrap();
+        assert_eq!(frame.descriptor, mip::IMU_DATA);
+    }
+
+    #[test]
+    fn pacing_spaces_commands_on_the_clock() {
+        let (port, clock) = connect(ScriptedDevice::new());
+        let pacing = Pacing {
+            command_delay: Duration::from_millis(20),
+            ..Pacing::default()
+        };
+        let mut port = transport::pace(Box::new(port), pacing, Arc::new(clock.clone()));
+
+        let start = clock.now();
+        device::send_raw(&mut *port, &clock, mip::PING, vec![], TIMEOUT).unwrap();
+        device::send_raw(&mut *port, &clock, mip::PING, vec![], TIMEOUT).unwrap();
+        assert!(clock.now() - start >= pacing.command_delay);
+    }
+
+    #[test]
+    fn pacing_waits_at_packet_starts_however_they_are_written() {
+        let (port, clock) = connect(ScriptedDevice::new());
+        let pacing = Pacing {
+            command_delay: Duration::from_millis(20),
+            ..Pacing::default()
+        };
+        let mut port = transport::pace(Box::new(port), pacing, Arc::new(clock.clone()));
+        let ping = mip::command(mip::PING, vec![]).encode().unwrap();
+
+        // Two packets in one write are still spaced out
+        let start = clock.now();
+        port.write_all(&[ping.clone(), ping.clone()].concat())
+            .unwrap();
+        assert_eq!(clock.now() - start, pacing.command_delay);
+
+        // A packet dribbled out a byte at a time only waits before its first byte
+        let start = clock.now();
+        for byte in &ping {
+            port.write_all(&[*byte]).unwrap();
+        }
+        assert_eq!(clock.now() - start, pacing.command_delay);
+    }
+}